@@ -0,0 +1,14 @@
+# Assembles the programs in user/ and packs them into the initrd embedded
+# in the kernel. User space starts at 0x1000_0000_0000.
+set -e
+cd "$(dirname "$0")/../user"
+mkdir -p ../target/user/bin
+for source in *.s; do
+    name="${source%.s}"
+    as --64 -o "../target/user/$name.o" "$source"
+    ld -static -nostdlib -z max-page-size=4096 -Ttext-segment=0x100000000000 \
+        -o "../target/user/bin/$name" "../target/user/$name.o"
+    strip "../target/user/bin/$name"
+done
+tar --format=ustar --owner=0 --group=0 --numeric-owner --mtime=@0 --sort=name \
+    -cf initrd.tar -C ../target/user/bin $(ls ../target/user/bin)
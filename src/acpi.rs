@@ -5,6 +5,9 @@ use acpi::{
     AcpiHandler,
     PhysicalMapping,
     AcpiTables,
+    HpetInfo,
+    InterruptModel,
+    platform::ProcessorState,
     fadt::Fadt,
     bgrt::Bgrt,
     madt::Madt,
@@ -18,13 +21,17 @@ use aml::{
     AmlValue,
     AmlName
 };
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use conquer_once::spin::OnceCell;
 
 pub(crate) static ACPI_INFO: OnceCell<AcpiInfo> = OnceCell::uninit();
 
 pub(crate) struct AcpiInfo {
     pub pm1a_control_block: Option<u64>,
+    pub hpet_base_address: Option<u64>,
+    pub local_apic_address: Option<u64>,
+    /// APIC IDs of the application processors that can be started.
+    pub application_processors: Vec<u32>,
     pub aml_context: Option<AmlContext>
 }
 
@@ -166,6 +173,21 @@ pub fn init_acpi_info(
         }
     }
 
+    let platform_info = acpi_tables.platform_info()?;
+    let local_apic_address = match &platform_info.interrupt_model {
+        InterruptModel::Apic(apic) => Some(apic.local_apic_address),
+        _ => None
+    };
+    let application_processors = platform_info.processor_info.as_ref()
+        .map(|processor_info| {
+            processor_info.application_processors.iter()
+                .filter(|processor| processor.state == ProcessorState::WaitingForSipi)
+                .map(|processor| processor.local_apic_id)
+                .collect()
+        })
+        .unwrap_or_default();
+    log::info!("{} application processors", application_processors.len());
+
     let fadt = unsafe { acpi_tables.get_sdt::<Fadt>(acpi::sdt::Signature::FADT)? } ;
     let pm1a_control_block = if let Some(fadt) = fadt {
         log::info!("fadt found");
@@ -176,6 +198,17 @@ pub fn init_acpi_info(
         None
     };
 
+    let hpet_base_address = match HpetInfo::new(&acpi_tables) {
+        Ok(hpet_info) => {
+            log::info!("hpet base_address=0x{:x}", hpet_info.base_address);
+            Some(hpet_info.base_address as u64)
+        },
+        Err(err) => {
+            log::info!("hpet not available: {:?}", err);
+            None
+        }
+    };
+
     if verbose && log::log_enabled!(log::Level::Trace) {
         let bgrt = unsafe { acpi_tables.get_sdt::<Bgrt>(acpi::sdt::Signature::BGRT)? } ;
         if let Some(bgrt) = bgrt {
@@ -215,6 +248,9 @@ pub fn init_acpi_info(
 
     ACPI_INFO.init_once(|| AcpiInfo {
         pm1a_control_block,
+        hpet_base_address,
+        local_apic_address,
+        application_processors,
         aml_context: Some(aml_context)
     });
 
@@ -230,6 +266,19 @@ fn parse_aml_table(handler: RicosAcpiHandler, context: &mut AmlContext, aml_tabl
     }
 }
 
+pub fn get_hpet_base_address() -> Option<u64> {
+    ACPI_INFO.get().and_then(|acpi_info| acpi_info.hpet_base_address)
+}
+
+pub fn get_local_apic_address() -> Option<u64> {
+    ACPI_INFO.get().and_then(|acpi_info| acpi_info.local_apic_address)
+}
+
+/// APIC IDs of the application processors waiting to be started.
+pub fn get_application_processors() -> &'static [u32] {
+    ACPI_INFO.get().map_or(&[], |acpi_info| &acpi_info.application_processors)
+}
+
 pub fn get_shutdown_info() -> Option<(u16,u16)> {
     if let Some(acpi_info) = ACPI_INFO.get() {
         if let Some(pm1a_control_block) = acpi_info.pm1a_control_block {
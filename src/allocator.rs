@@ -6,6 +6,7 @@ use x86_64::{
     },
     VirtAddr,
 };
+use crate::spinlock::{SpinLock, SpinLockGuard};
 // use linked_list_allocator::LockedHeap;
 // use bump::BumpAllocator;
 // use linked_list::LinkedListAllocator;
@@ -69,19 +70,23 @@ pub fn init_heap(
     Ok(())
 }
 
-/// A wrapper around spin::Mutex to permit trait implementations.
+/// A wrapper around `SpinLock` to permit trait implementations.
+///
+/// Interrupts stay disabled while allocating, a preempted thread holding the
+/// lock would stall every other thread.
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: SpinLock<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: SpinLock::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<A> {
         self.inner.lock()
     }
 }
@@ -0,0 +1,96 @@
+//! Local APIC, used to start and signal the other CPUs.
+//!
+//! Every CPU sees its own local APIC at the same address, so these functions
+//! act on the APIC of the CPU calling them.
+
+use core::{hint::spin_loop, sync::atomic::{AtomicU64, Ordering}};
+use x86_64::{instructions::interrupts, VirtAddr};
+use crate::memory;
+
+/// Vector the APIC raises spurious interrupts on, they need no EOI.
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xFF;
+/// Vector of the IPI that gets a halted CPU to look for work.
+pub const WAKE_UP_VECTOR: u8 = 0xF0;
+
+const ID: u64 = 0x20;
+const EOI: u64 = 0xB0;
+const SPURIOUS_INTERRUPT: u64 = 0xF0;
+const INTERRUPT_COMMAND_LOW: u64 = 0x300;
+const INTERRUPT_COMMAND_HIGH: u64 = 0x310;
+
+const APIC_ENABLE: u32 = 1 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+/// Virtual address of the registers, zero until `init`.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Uses the registers at `physical_address`, found in the ACPI MADT, through
+/// the physical memory mapping.
+pub fn init(physical_address: u64) {
+    let base = memory::physical_memory_offset() + physical_address;
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+}
+
+pub fn is_initialized() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+fn register(offset: u64) -> *mut u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    assert!(base != 0, "local APIC not initialized");
+    (VirtAddr::new(base) + offset).as_mut_ptr()
+}
+
+fn read(offset: u64) -> u32 {
+    unsafe { register(offset).read_volatile() }
+}
+
+fn write(offset: u64, value: u32) {
+    unsafe { register(offset).write_volatile(value) }
+}
+
+/// APIC ID of the current CPU.
+pub fn id() -> u32 {
+    read(ID) >> 24
+}
+
+/// Software enables the APIC of the current CPU, its local interrupts stay
+/// masked.
+pub fn enable() {
+    write(SPURIOUS_INTERRUPT, APIC_ENABLE | SPURIOUS_INTERRUPT_VECTOR as u32);
+}
+
+/// Signals the end of an interrupt delivered by the APIC.
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+/// Sends an INIT IPI, which resets the CPU to wait for a startup IPI.
+pub fn send_init(apic_id: u32) {
+    send_ipi(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI, starting the CPU in real mode at `page` * 4 KiB.
+pub fn send_startup(apic_id: u32, page: u8) {
+    send_ipi(apic_id, DELIVERY_STARTUP | LEVEL_ASSERT | page as u32);
+}
+
+/// Sends a fixed IPI on `WAKE_UP_VECTOR`.
+pub fn send_ipi_wake_up(apic_id: u32) {
+    send_ipi(apic_id, LEVEL_ASSERT | WAKE_UP_VECTOR as u32);
+}
+
+fn send_ipi(apic_id: u32, command: u32) {
+    assert!(apic_id <= 0xFF, "APIC ID {} needs x2APIC", apic_id);
+    interrupts::without_interrupts(|| {
+        write(INTERRUPT_COMMAND_HIGH, apic_id << 24);
+        // writing the low half sends it
+        write(INTERRUPT_COMMAND_LOW, command);
+        while read(INTERRUPT_COMMAND_LOW) & DELIVERY_PENDING != 0 {
+            spin_loop();
+        }
+    });
+}
@@ -0,0 +1,18 @@
+//! Kernel clipboard, filled by selecting text with the mouse and pasted
+//! into the shell input line with the middle button or Shift+Insert.
+
+use alloc::string::String;
+use crate::spinlock::SpinLock;
+
+static CLIPBOARD: SpinLock<String> = SpinLock::new(String::new());
+
+/// Replaces the contents of the clipboard with `text`.
+pub fn copy(text: String) {
+    let previous = core::mem::replace(&mut *CLIPBOARD.lock(), text);
+    // freed with interrupts enabled again
+    drop(previous);
+}
+
+pub fn paste() -> String {
+    CLIPBOARD.lock().clone()
+}
@@ -1,11 +1,15 @@
 use spin::Mutex;
-use pc_keyboard::{DecodedKey, KeyCode};
 use crate::{
+    clipboard,
+    console,
+    task::input::{self, InputEvent, KeyCode, MouseButton, MouseEvent},
     vga_buffer,
     print,
     println,
     eprintln,
-    error::Error
+    error::Error,
+    process::Process,
+    task::join::JoinHandle
 };
 use alloc::{
     str::FromStr,
@@ -17,13 +21,48 @@ use crate::acpi;
 
 pub static COMMAND_PROCESSOR: Mutex<CommandProcessor> = Mutex::new(CommandProcessor::new());
 
+/// Rows Shift+PageUp and Shift+PageDown scroll by, keeping one in view.
+const PAGE_LINES: isize = vga_buffer::BUFFER_HEIGHT as isize - 1;
+/// Rows a notch of the mouse wheel scrolls by.
+const WHEEL_LINES: isize = 3;
+
 pub struct CommandProcessor {
-    command_buffer: Vec<char>
+    command_buffer: Vec<char>,
+    /// Started by the last command, Ctrl+C interrupts it while it runs.
+    foreground: Option<Foreground>
+}
+
+/// What a command leaves running after it returns.
+enum Foreground {
+    Process(Process),
+    Task(JoinHandle<()>),
+}
+
+impl Foreground {
+    fn is_finished(&self) -> bool {
+        match self {
+            Self::Process(process) => process.is_finished(),
+            Self::Task(handle) => handle.is_finished()
+        }
+    }
+
+    fn interrupt(&self) {
+        match self {
+            Self::Process(process) => process.interrupt(),
+            Self::Task(handle) => handle.abort()
+        }
+    }
 }
 
 enum Command {
     Help,
     Uptime,
+    Date,
+    Ps,
+    Run,
+    Cpus,
+    Keymap,
+    Scrollback,
     Color,
     Clear,
     #[cfg(feature="pc-speaker")]
@@ -45,6 +84,12 @@ impl FromStr for Command {
         match command {
             "help" => Ok(Self::Help),
             "uptime" => Ok(Self::Uptime),
+            "date" => Ok(Self::Date),
+            "ps" => Ok(Self::Ps),
+            "run" => Ok(Self::Run),
+            "cpus"|"nproc" => Ok(Self::Cpus),
+            "keymap" => Ok(Self::Keymap),
+            "scrollback" => Ok(Self::Scrollback),
             "color" => Ok(Self::Color),
             "clear" => Ok(Self::Clear),
             #[cfg(feature="pc-speaker")]
@@ -54,7 +99,7 @@ impl FromStr for Command {
             "window" => Ok(Self::Window),
             "echo" => Ok(Self::Echo),
             "panic" => Ok(Self::Panic),
-            "exit"|"quit"|"shutdown" => Ok(Self::Exit),
+            "exit"|"quit"|"shutdown"|"logout" => Ok(Self::Exit),
             #[cfg(feature="acpi-feat")]
             "acpi-shutdown-info" => Ok(Self::AcpiShutdownInfo),
             _ => Err(Error::InvalidCommand)
@@ -62,51 +107,88 @@ impl FromStr for Command {
     }
 }
 
+/// Runs the shell on the input while it has the focus.
+pub async fn run_shell() {
+    let mut focus = input::grab_focus();
+    while let Some(event) = focus.recv().await {
+        // a process waiting for input takes precedence over the shell
+        if let InputEvent::Text(character) = event {
+            vga_buffer::show_live();
+            if console::handle_input(character) {
+                continue;
+            }
+        }
+        COMMAND_PROCESSOR.lock().process_event(event);
+    }
+}
+
 impl CommandProcessor {
     const fn new() -> Self {
         CommandProcessor {
-            command_buffer: Vec::new()
+            command_buffer: Vec::new(),
+            foreground: None
         }
     }
 
-    pub fn process_key(&mut self, key: DecodedKey) {
-        match key {
-            DecodedKey::Unicode(character) => match character {
+    pub fn process_event(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Text(character) => match character {
                 '\u{0008}' => {//backspace
                     vga_buffer::erase_character();
                     self.remove_character();
                 },
+                '\u{0003}' => self.interrupt(),//ctrl+c
+                '\u{0004}' => {//ctrl+d
+                    // ends the input of a process reading it, which the
+                    // console handles, the shell itself never ends
+                    if self.command_buffer.is_empty() {
+                        println!("Use \"exit\" to shut down.");
+                    }
+                },
                 '\u{0009}' => {},//tab
                 '\u{000A}' => {//enter
                     println!();
                     self.finish_command();
                 },
+                '\u{000C}' => {//ctrl+l
+                    vga_buffer::clear();
+                    print!("{}", self.command_buffer.iter().collect::<String>());
+                },
+                '\u{0015}' => {//ctrl+u
+                    let len = self.command_buffer.len();
+                    self.erase_characters(len);
+                },
+                '\u{0017}' => {//ctrl+w
+                    let word = self.command_buffer.iter().rev()
+                        .skip_while(|character| character.is_whitespace())
+                        .take_while(|character| !character.is_whitespace())
+                        .count();
+                    let spaces = self.command_buffer.iter().rev()
+                        .take_while(|character| character.is_whitespace())
+                        .count();
+                    self.erase_characters(spaces + word);
+                },
                 '\u{001B}' => {},//esc
+                character if character.is_control() => {},
                 _ => {
                     print!("{}", character);
                     self.add_character(character);
                 }
             },
-            DecodedKey::RawKey(key) => match key {
+            InputEvent::Key(key) if key.pressed => match key.code {
+                KeyCode::Insert if key.modifiers.is_shifted() => self.paste(),
+                KeyCode::PageUp if key.modifiers.is_shifted() => vga_buffer::scroll_view(PAGE_LINES),
+                KeyCode::PageDown if key.modifiers.is_shifted() => vga_buffer::scroll_view(-PAGE_LINES),
                 KeyCode::ArrowUp => vga_buffer::cursor_position_delta(0, 1),
                 KeyCode::ArrowDown => vga_buffer::cursor_position_delta(0, -1),
                 KeyCode::ArrowLeft => vga_buffer::cursor_position_delta(-1, 0),
                 KeyCode::ArrowRight => vga_buffer::cursor_position_delta(1, 0),
-                KeyCode::AltLeft => {},
-                KeyCode::F1 => {},
-                KeyCode::F2 => {},
-                KeyCode::F3 => {},
-                KeyCode::F4 => {},
-                KeyCode::F5 => {},
-                KeyCode::F6 => {},
-                KeyCode::F7 => {},
-                KeyCode::F8 => {},
-                KeyCode::F9 => {},
-                KeyCode::F10 => {},
-                KeyCode::F11 => {},
-                KeyCode::F12 => {},
-                _ => log::info!("keycode {:?}", key)
-            }
+                _ => {}
+            },
+            InputEvent::Mouse(MouseEvent::Button { button: MouseButton::Middle, pressed: true }) => self.paste(),
+            // turning the wheel towards the user goes towards the live screen
+            InputEvent::Mouse(MouseEvent::Scroll { lines }) => vga_buffer::scroll_view(-(lines as isize) * WHEEL_LINES),
+            _ => {}
         }
     }
 
@@ -118,6 +200,34 @@ impl CommandProcessor {
         self.command_buffer.pop();
     }
 
+    fn erase_characters(&mut self, count: usize) {
+        for _ in 0..count {
+            vga_buffer::erase_character();
+            self.remove_character();
+        }
+    }
+
+    /// Types the clipboard into the input line, line breaks become spaces
+    /// so pasting doesn't run anything.
+    fn paste(&mut self) {
+        let text: String = clipboard::paste().chars()
+            .map(|character| if character == '\n' { ' ' } else { character })
+            .filter(|character| !character.is_control())
+            .collect();
+        print!("{}", text);
+        self.command_buffer.extend(text.chars());
+    }
+
+    /// Interrupts the foreground process or task if it's still running,
+    /// otherwise drops the input line.
+    fn interrupt(&mut self) {
+        println!("^C");
+        match self.foreground.take().filter(|foreground| !foreground.is_finished()) {
+            Some(foreground) => foreground.interrupt(),
+            None => self.command_buffer.clear()
+        }
+    }
+
     fn finish_command(&mut self) {
         if !self.command_buffer.is_empty() {
             let command: String = self.command_buffer.iter().collect();
@@ -128,7 +238,7 @@ impl CommandProcessor {
         }
     }
 
-    fn process_command(&self, original_command: &str) -> Result<(),Error> {
+    fn process_command(&mut self, original_command: &str) -> Result<(),Error> {
         let mut args = original_command.split_ascii_whitespace();
         if let Some(command)  = args.next() {
             if original_command.find(command).unwrap() > 0 {
@@ -140,10 +250,19 @@ impl CommandProcessor {
             match command {
                 Command::Help => self.help(),
                 Command::Uptime => Ok(println!("System uptime is {:#?}", crate::time::get_system_uptime())),
+                Command::Date => self.date(args),
+                Command::Ps => self.ps(),
+                Command::Run => self.run(args),
+                Command::Cpus => self.cpus(),
+                Command::Keymap => self.keymap(args),
+                Command::Scrollback => self.scrollback(args),
                 Command::Color => self.set_colors(args),
                 Command::Clear => Ok(crate::vga_buffer::clear()),
                 #[cfg(feature="pc-speaker")]
-                Command::Beep => Ok(crate::pc_speaker::beep()),
+                Command::Beep => {
+                    self.foreground = Some(Foreground::Task(crate::pc_speaker::beep()));
+                    Ok(())
+                },
                 Command::Chars => {
                     println!("\u{0000}☺☻♥♦♣♠•◘○\\n♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼");
                     // println!("\u{0000}☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼");
@@ -199,6 +318,116 @@ impl CommandProcessor {
         }
     }
 
+    fn date(&self, args: Vec<&str>) -> Result<(),Error> {
+        if args.is_empty() {
+            println!("{}", crate::rtc::now());
+            Ok(())
+        } else {
+            let date_time = args.join(" ").parse()?;
+            crate::rtc::set_date_time(&date_time);
+            Ok(())
+        }
+    }
+
+    fn ps(&self) -> Result<(),Error> {
+        use crate::task::{executor, Priority, TaskState};
+        use crate::thread::ThreadState;
+
+        let now = crate::time::Instant::now();
+        println!("{:>5} {:<8} {:<12} {:>8} {:>12} {:>9}  NAME", "ID", "STATE", "PRIORITY", "POLLS", "POLL TIME", "AGE");
+        for task in executor::task_snapshot() {
+            let state = match task.state {
+                TaskState::Ready => "ready",
+                TaskState::Pending => "pending"
+            };
+            let priority = match task.priority {
+                Priority::BottomHalf => "bottom-half",
+                Priority::Interactive => "interactive",
+                Priority::Background => "background"
+            };
+            println!("{:>5} {:<8} {:<12} {:>8} {:>10}us {:>8}s  {}",
+                task.id,
+                state,
+                priority,
+                task.poll_count,
+                task.poll_time.as_micros(),
+                now.duration_since(task.spawn_time).as_secs(),
+                task.name);
+        }
+
+        println!();
+        println!("{:>5} {:<8}  NAME", "TID", "STATE");
+        for thread in crate::thread::thread_snapshot() {
+            let state = match thread.state {
+                ThreadState::Running => "running",
+                ThreadState::Ready => "ready",
+                ThreadState::Sleeping(_) => "sleeping",
+                ThreadState::Parked => "parked",
+                ThreadState::Exited => "exited"
+            };
+            println!("{:>5} {:<8}  {}", thread.id.as_u64(), state, thread.name);
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, args: Vec<&str>) -> Result<(),Error> {
+        let program = match args.first() {
+            Some(program) => *program,
+            None => {
+                print!("Available programs:");
+                for file in crate::initrd::files() {
+                    print!(" {}", file.name);
+                }
+                println!();
+                return Ok(());
+            }
+        };
+        let executable = crate::initrd::find(program).ok_or(Error::ProgramNotFound)?;
+        // the shell keeps running, the exit status gets logged
+        let process = crate::process::spawn_elf(String::from(program), executable, &args, &[])?;
+        println!("Started process {}, Ctrl+C interrupts it", process.pid().as_u64());
+        self.foreground = Some(Foreground::Process(process));
+        Ok(())
+    }
+
+    fn cpus(&self) -> Result<(),Error> {
+        println!("{} CPUs online", crate::smp::online_cpus());
+        println!("{:>4} {:>7}  STATE", "CPU", "APIC ID");
+        for cpu in crate::smp::cpu_snapshot() {
+            let state = if cpu.online { "online" } else { "failed" };
+            println!("{:>4} {:>7}  {}", cpu.id, cpu.apic_id, state);
+        }
+        Ok(())
+    }
+
+    fn keymap(&self, args: Vec<&str>) -> Result<(),Error> {
+        use crate::keymap::{self, Keymap};
+
+        match args.as_slice() {
+            [] => {
+                print!("Keymap: {}, available:", keymap::current());
+                for keymap in Keymap::ALL {
+                    print!(" {}", keymap);
+                }
+                println!();
+                Ok(())
+            },
+            [name] => Ok(keymap::set(name.parse()?)),
+            _ => Err(Error::WrongNumberOfArguments(1))
+        }
+    }
+
+    fn scrollback(&self, args: Vec<&str>) -> Result<(),Error> {
+        match args.as_slice() {
+            [] => {
+                println!("Scrollback: {} lines, Shift+PageUp/PageDown to browse", vga_buffer::scrollback_lines());
+                Ok(())
+            },
+            [lines] => vga_buffer::set_scrollback_lines(lines.parse()?),
+            _ => Err(Error::WrongNumberOfArguments(1))
+        }
+    }
+
     fn draw_window_frame(&self, args: Vec<&str>) -> Result<(),Error> {
         let args = args.iter().map(|arg| arg.parse()).collect::<Result<Vec<_>,_>>()?;
 
@@ -216,6 +445,12 @@ impl CommandProcessor {
         println!("╟──────────────────────────────────────────────────────────────────────────────┤");
         println!("║* help: prints this help                                                      │");
         println!("║* uptime: prints system uptime                                                │");
+        println!("║* date [YYYY-MM-DD HH:MM:SS]: prints or sets the date and time                │");
+        println!("║* ps: lists running tasks and threads                                         │");
+        println!("║* run [program args...]: runs a program from the initrd, or lists them        │");
+        println!("║* cpus/nproc: lists the processors and how many are online                    │");
+        println!("║* keymap [us|uk|de|abnt2]: prints or switches the keyboard layout             │");
+        println!("║* scrollback [lines]: prints or sets the rows kept, Shift+PageUp/Down browse  │");
         println!("║* color foreground background: changes screen colors                          │");
         #[cfg(feature="pc-speaker")]
         println!("║* beep: beeps pc speaker                                                      │");
@@ -224,7 +459,7 @@ impl CommandProcessor {
         println!("║* chars2: prints all characters                                               │");
         println!("║* clear: clears the screen buffer                                             │");
         println!("║* panic [reason]: panics with optional reason                                 │");
-        println!("║* exit/quit/shutdown: shuts down the computer                                 │");
+        println!("║* exit/quit/shutdown/logout: shuts down the computer                          │");
         #[cfg(feature="acpi-feat")]
         println!("║* acpi-shutdown-info: prints shutdown info from acpi                          │");
         println!("╚══════════════════════════════════════════════════════════════════════════════╛");
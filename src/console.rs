@@ -0,0 +1,80 @@
+use alloc::collections::VecDeque;
+use crate::{process, spinlock::SpinLock, thread::{self, ThreadId}};
+
+/// Ctrl+C, left to the shell to interrupt the foreground process.
+const INTERRUPT: char = '\u{0003}';
+/// Ctrl+D, ends the input of the reader.
+const END_OF_INPUT: char = '\u{0004}';
+
+/// Keyboard input waiting to be read, and the thread blocked in `read`.
+static INPUT: SpinLock<Input> = SpinLock::new(Input { buffer: VecDeque::new(), end_of_input: false, reader: None });
+
+struct Input {
+    buffer: VecDeque<u8>,
+    /// Set by Ctrl+D, makes the next `read` with an empty buffer return 0.
+    end_of_input: bool,
+    reader: Option<ThreadId>,
+}
+
+/// Called by the shell task
+///
+/// Hands `character` to the thread blocked in `read`, if there is one,
+/// returning whether it was taken. Everything else goes to the shell.
+pub(crate) fn handle_input(character: char) -> bool {
+    if character == INTERRUPT {
+        return false;
+    }
+    let reader = {
+        let mut input = INPUT.lock();
+        let reader = input.reader.take();
+        if reader.is_some() {
+            if character == END_OF_INPUT {
+                input.end_of_input = true;
+            } else {
+                let mut encoded = [0; 4];
+                input.buffer.extend(character.encode_utf8(&mut encoded).bytes());
+            }
+        }
+        reader
+    };
+    match reader {
+        Some(reader) => {
+            thread::unpark(reader);
+            true
+        },
+        None => false
+    }
+}
+
+/// Blocks the current thread until there is keyboard input, then copies as
+/// much of it as fits to `buf`, returning the number of bytes copied.
+///
+/// Characters are UTF-8 encoded and not echoed. Returns 0 after Ctrl+D, or
+/// when the process reading is interrupted.
+pub fn read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    let id = thread::current_id().expect("threads not initialized");
+    loop {
+        let interrupted = process::is_interrupted();
+        let (read, ended) = {
+            let mut input = INPUT.lock();
+            let len = buf.len().min(input.buffer.len());
+            let ended = len == 0 && (core::mem::take(&mut input.end_of_input) || interrupted);
+            if len == 0 && !ended {
+                input.reader = Some(id);
+            } else if input.reader == Some(id) {
+                input.reader = None;
+            }
+            for (byte, input) in buf.iter_mut().zip(input.buffer.drain(..len)) {
+                *byte = input;
+            }
+            (len, ended)
+        };
+        if read > 0 || ended {
+            return read;
+        }
+        thread::park();
+    }
+}
@@ -1,4 +1,5 @@
 use core::{fmt, num::ParseIntError};
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
 #[cfg(feature="acpi-feat")]
 use aml::AmlError as AmlCrateError;
 #[cfg(feature="acpi-feat")]
@@ -11,6 +12,14 @@ pub enum Error {
     NumericArgumentExpected,
     InvalidCommand,
     ColorParseError,
+    InvalidDateTime,
+    MemoryMapping(MapToError<Size4KiB>),
+    InvalidElf(&'static str),
+    ProgramNotFound,
+    ArgumentListTooLong,
+    UnknownKeymap,
+    ScrollbackTooLong,
+    OutOfMemory,
     #[cfg(feature="acpi-feat")]
     AcpiError(AcpiCrateError),
     #[cfg(feature="acpi-feat")]
@@ -23,6 +32,12 @@ impl From<ParseIntError> for Error {
     }
 }
 
+impl From<MapToError<Size4KiB>> for Error {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        Error::MemoryMapping(err)
+    }
+}
+
 #[cfg(feature="acpi-feat")]
 impl From<AcpiCrateError> for Error {
     fn from(err: AcpiCrateError) -> Self {
@@ -44,6 +59,14 @@ impl fmt::Display for Error {
             Self::NumericArgumentExpected => write!(f, "Numeric arguments expected."),
             Self::InvalidCommand => write!(f, "Invalid command."),
             Self::ColorParseError => write!(f, "Error parsing color."),
+            Self::InvalidDateTime => write!(f, "Invalid date/time, expected YYYY-MM-DD HH:MM:SS."),
+            Self::MemoryMapping(err) => write!(f, "Memory mapping failed: {:?}", err),
+            Self::InvalidElf(reason) => write!(f, "Invalid executable, {}.", reason),
+            Self::ProgramNotFound => write!(f, "Program not found."),
+            Self::ArgumentListTooLong => write!(f, "Argument list too long."),
+            Self::UnknownKeymap => write!(f, "Unknown keymap, expected us, uk, de or abnt2."),
+            Self::ScrollbackTooLong => write!(f, "Scrollback can keep at most {} lines.", crate::vga_buffer::MAX_SCROLLBACK_LINES),
+            Self::OutOfMemory => write!(f, "Out of memory."),
             #[cfg(feature="acpi-feat")]
             Self::AcpiError(err) => write!(f, "AcpiError: {:?}", err),
             #[cfg(feature="acpi-feat")]
@@ -9,51 +9,114 @@ use x86_64::{
         }
     }
 };
+use alloc::{boxed::Box, vec};
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+/// TSS of the bootstrap processor, mutable so the stack used for interrupts
+/// from user mode can follow the running thread, see `set_kernel_stack`.
+pub(crate) static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096;
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
+        let tss = unsafe {
+            let tss = &mut *addr_of_mut!(TSS);
+            tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+                static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+                let stack_start = VirtAddr::from_ptr(&*addr_of!(STACK));
+                let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
+                stack_end
+            };
+            &*addr_of!(TSS)
+        };
+        new_gdt(tss)
     };
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-pub fn init() {
+/// Creates a GDT using `tss`, the selectors are the same for every CPU.
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    // `sysret` expects the user data segment right before the user code
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (gdt, Selectors { code_selector, data_selector, user_code_selector, user_data_selector, tss_selector })
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
     use x86_64::{
         instructions::{
-            segmentation::CS,
+            segmentation::{CS, DS, ES, SS},
             tables::load_tss
         },
         registers::segmentation::Segment
     };
 
-    GDT.0.load();
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.code_selector);
+        SS::set_reg(selectors.data_selector);
+        DS::set_reg(selectors.data_selector);
+        ES::set_reg(selectors.data_selector);
+        load_tss(selectors.tss_selector);
     }
-}
\ No newline at end of file
+}
+
+pub fn init() {
+    load(&GDT.0, &GDT.1);
+}
+
+/// Loads a GDT and TSS of its own on an application processor, with its own
+/// double fault stack. They are never freed.
+pub fn init_ap() {
+    let stack = Box::leak(vec![0u8; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    let mut tss = Box::new(TaskStateSegment::new());
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+    let tss: &'static TaskStateSegment = Box::leak(tss);
+    let (gdt, selectors) = new_gdt(tss);
+    load(Box::leak(Box::new(gdt)), &selectors);
+}
+
+pub fn kernel_code_selector() -> SegmentSelector {
+    GDT.1.code_selector
+}
+
+pub fn kernel_data_selector() -> SegmentSelector {
+    GDT.1.data_selector
+}
+
+pub fn user_code_selector() -> SegmentSelector {
+    GDT.1.user_code_selector
+}
+
+pub fn user_data_selector() -> SegmentSelector {
+    GDT.1.user_data_selector
+}
+
+/// Sets the stack the CPU switches to on interrupts and exceptions from
+/// user mode.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe { (*addr_of_mut!(TSS)).privilege_stack_table[0] = stack_top };
+}
+
+/// Pointer to the kernel stack of `set_kernel_stack`, for code entering user
+/// mode that only knows its stack pointer at the last moment.
+pub(crate) fn kernel_stack_slot() -> *mut u64 {
+    unsafe { addr_of_mut!((*addr_of_mut!(TSS)).privilege_stack_table[0]) as *mut u64 }
+}
@@ -0,0 +1,245 @@
+use x86_64::VirtAddr;
+use conquer_once::spin::OnceCell;
+use core::time::Duration;
+
+const CAPABILITIES_REGISTER: u64 = 0x000;
+const CONFIGURATION_REGISTER: u64 = 0x010;
+const INTERRUPT_STATUS_REGISTER: u64 = 0x020;
+const MAIN_COUNTER_REGISTER: u64 = 0x0F0;
+const TIMER_CONFIGURATION_REGISTER: u64 = 0x100;
+const TIMER_COMPARATOR_REGISTER: u64 = 0x108;
+const TIMER_REGISTERS_STRIDE: u64 = 0x20;
+
+const CAPABILITY_LEGACY_REPLACEMENT: u64 = 1 << 15;
+const CAPABILITY_64_BIT_COUNTER: u64 = 1 << 13;
+
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+const CONFIGURATION_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_SET_ACCUMULATOR: u64 = 1 << 6;
+const TIMER_32_BIT_MODE: u64 = 1 << 8;
+
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+
+pub static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+/// How a comparator fires once it's armed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
+}
+
+//https://wiki.osdev.org/HPET
+pub struct Hpet {
+    base: VirtAddr,
+    period_femtoseconds: u64,
+    timer_count: u8,
+    counter_is_64_bit: bool,
+    legacy_replacement_capable: bool,
+}
+
+impl Hpet {
+    /// Creates the driver for the registers mapped at `base`.
+    ///
+    /// This function is unsafe because the caller must guarantee that `base`
+    /// points to the HPET register block.
+    unsafe fn new(base: VirtAddr) -> Hpet {
+        let mut hpet = Hpet {
+            base,
+            period_femtoseconds: 0,
+            timer_count: 0,
+            counter_is_64_bit: false,
+            legacy_replacement_capable: false,
+        };
+        let capabilities = hpet.read(CAPABILITIES_REGISTER);
+        hpet.period_femtoseconds = capabilities >> 32;
+        hpet.timer_count = ((capabilities >> 8) & 0x1F) as u8 + 1;
+        hpet.counter_is_64_bit = capabilities & CAPABILITY_64_BIT_COUNTER != 0;
+        hpet.legacy_replacement_capable = capabilities & CAPABILITY_LEGACY_REPLACEMENT != 0;
+        hpet
+    }
+
+    fn read(&self, offset: u64) -> u64 {
+        unsafe { (self.base + offset).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write(&self, offset: u64, value: u64) {
+        unsafe { (self.base + offset).as_mut_ptr::<u64>().write_volatile(value) }
+    }
+
+    fn timer_register(timer: u8, register: u64) -> u64 {
+        register + timer as u64 * TIMER_REGISTERS_STRIDE
+    }
+
+    /// Length of a main counter tick.
+    pub fn period_femtoseconds(&self) -> u64 {
+        self.period_femtoseconds
+    }
+
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_femtoseconds
+    }
+
+    pub fn timer_count(&self) -> u8 {
+        self.timer_count
+    }
+
+    pub fn enable(&self) {
+        let configuration = self.read(CONFIGURATION_REGISTER);
+        self.write(CONFIGURATION_REGISTER, configuration | CONFIGURATION_ENABLE);
+    }
+
+    pub fn disable(&self) {
+        let configuration = self.read(CONFIGURATION_REGISTER);
+        self.write(CONFIGURATION_REGISTER, configuration & !CONFIGURATION_ENABLE);
+    }
+
+    pub fn counter(&self) -> u64 {
+        if self.counter_is_64_bit {
+            self.read(MAIN_COUNTER_REGISTER)
+        } else {
+            self.read(MAIN_COUNTER_REGISTER) & 0xFFFF_FFFF
+        }
+    }
+
+    pub fn duration_to_ticks(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * FEMTOSECONDS_PER_NANOSECOND as u128 / self.period_femtoseconds as u128) as u64
+    }
+
+    pub fn ticks_to_duration(&self, ticks: u64) -> Duration {
+        Duration::from_nanos((ticks as u128 * self.period_femtoseconds as u128 / FEMTOSECONDS_PER_NANOSECOND as u128) as u64)
+    }
+
+    /// Routes timer 0 to IRQ 0 and timer 1 to IRQ 8, disconnecting the PIT and
+    /// the RTC from the PIC.
+    pub fn enable_legacy_replacement(&self) -> bool {
+        if !self.legacy_replacement_capable {
+            return false;
+        }
+        let configuration = self.read(CONFIGURATION_REGISTER);
+        self.write(CONFIGURATION_REGISTER, configuration | CONFIGURATION_LEGACY_REPLACEMENT);
+        true
+    }
+
+    pub fn disable_legacy_replacement(&self) {
+        let configuration = self.read(CONFIGURATION_REGISTER);
+        self.write(CONFIGURATION_REGISTER, configuration & !CONFIGURATION_LEGACY_REPLACEMENT);
+    }
+
+    pub fn supports_periodic(&self, timer: u8) -> bool {
+        self.read(Self::timer_register(timer, TIMER_CONFIGURATION_REGISTER)) & TIMER_PERIODIC_CAPABLE != 0
+    }
+
+    /// Whether the main counter is at or past `comparator`.
+    fn reached(&self, comparator: u64) -> bool {
+        let elapsed = self.counter().wrapping_sub(comparator);
+        if self.counter_is_64_bit {
+            elapsed as i64 >= 0
+        } else {
+            elapsed as u32 as i32 >= 0
+        }
+    }
+
+    /// Arms comparator `timer` to fire after `duration`, and every `duration`
+    /// after that in periodic mode. The main counter keeps running.
+    ///
+    /// The comparator only fires when the counter goes past it, so if the
+    /// counter got there before it was written, it's armed again further
+    /// ahead. Returns false if it still couldn't be armed in time.
+    ///
+    /// Interrupt routing is left as configured, so legacy replacement must be
+    /// enabled for timers 0 and 1 to reach the PIC.
+    pub fn start_timer(&self, timer: u8, mode: TimerMode, duration: Duration) -> bool {
+        const MAX_ATTEMPTS: u32 = 8;
+        assert!(timer < self.timer_count, "HPET timer {} doesn't exist", timer);
+
+        let configuration_register = Self::timer_register(timer, TIMER_CONFIGURATION_REGISTER);
+        let comparator_register = Self::timer_register(timer, TIMER_COMPARATOR_REGISTER);
+        let ticks = self.duration_to_ticks(duration).max(1);
+
+        // 32 bit mode keeps the comparator in range of a 32 bit main counter
+        let mut configuration = self.read(configuration_register)
+            & !(TIMER_PERIODIC | TIMER_SET_ACCUMULATOR | TIMER_32_BIT_MODE);
+        if !self.counter_is_64_bit {
+            configuration |= TIMER_32_BIT_MODE;
+        }
+        configuration |= TIMER_INTERRUPT_ENABLE;
+
+        if mode == TimerMode::Periodic {
+            assert!(self.supports_periodic(timer), "HPET timer {} isn't periodic capable", timer);
+        }
+
+        let mut delay = ticks;
+        for _ in 0..MAX_ATTEMPTS {
+            let comparator = self.counter().wrapping_add(delay);
+            match mode {
+                TimerMode::OneShot => {
+                    self.write(configuration_register, configuration);
+                    self.write(comparator_register, comparator);
+                },
+                TimerMode::Periodic => {
+                    // the first write sets the first deadline, the second one
+                    // the period added to it every time it fires
+                    self.write(configuration_register, configuration | TIMER_PERIODIC | TIMER_SET_ACCUMULATOR);
+                    self.write(comparator_register, comparator);
+                    self.write(comparator_register, ticks);
+                }
+            }
+            if !self.reached(comparator) {
+                return true;
+            }
+            delay = delay.saturating_mul(2);
+        }
+        false
+    }
+
+    pub fn stop_timer(&self, timer: u8) {
+        let configuration_register = Self::timer_register(timer, TIMER_CONFIGURATION_REGISTER);
+        let configuration = self.read(configuration_register);
+        self.write(configuration_register, configuration & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+    }
+
+    /// Clears the status bit of a level triggered timer interrupt.
+    pub fn acknowledge_interrupt(&self, timer: u8) {
+        self.write(INTERRUPT_STATUS_REGISTER, 1 << timer);
+    }
+}
+
+/// Maps the HPET found in the ACPI tables and starts its main counter.
+///
+/// Must be called after `acpi::init_acpi_info` and `memory::init`, it does
+/// nothing if there is no HPET.
+pub fn init() {
+    let base_address = match crate::acpi::get_hpet_base_address() {
+        Some(base_address) => base_address,
+        None => return
+    };
+
+    let hpet = unsafe { Hpet::new(crate::memory::physical_memory_offset() + base_address) };
+    if hpet.period_femtoseconds == 0 || hpet.period_femtoseconds > 100_000_000 {
+        log::error!("invalid HPET period: {} fs", hpet.period_femtoseconds);
+        return;
+    }
+    for timer in 0..hpet.timer_count {
+        hpet.stop_timer(timer);
+    }
+    hpet.enable();
+    log::info!("HPET: frequency={} Hz, timers={}, 64bit={}",
+        hpet.frequency(), hpet.timer_count, hpet.counter_is_64_bit);
+
+    HPET.init_once(|| hpet);
+}
+
+/// Replaces the PIT as the source of the timer interrupt, ticking every `rate`.
+pub fn start_tick(rate: Duration) -> bool {
+    match HPET.get() {
+        Some(hpet) if hpet.supports_periodic(0) && hpet.enable_legacy_replacement() => {
+            hpet.start_timer(0, TimerMode::Periodic, rate)
+        },
+        _ => false
+    }
+}
@@ -0,0 +1,67 @@
+//! Programs embedded in the kernel as a USTAR archive, built from `user/` by
+//! `scripts/build_initrd.sh`.
+
+const BLOCK_SIZE: usize = 512;
+
+static INITRD: &[u8] = include_bytes!("../user/initrd.tar");
+
+/// Regular file of the initrd.
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    pub name: &'static str,
+    pub data: &'static [u8],
+}
+
+/// Lists the regular files of the initrd in archive order.
+pub fn files() -> impl Iterator<Item = File> {
+    Entries { archive: INITRD }.flatten()
+}
+
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    files().find(|file| file.name == name).map(|file| file.data)
+}
+
+/// Archive entries, `None` for those that aren't regular files.
+struct Entries {
+    archive: &'static [u8],
+}
+
+impl Iterator for Entries {
+    type Item = Option<File>;
+
+    fn next(&mut self) -> Option<Option<File>> {
+        let archive = self.archive;
+        let header = archive.get(..BLOCK_SIZE)?;
+        // the archive ends with zeroed blocks
+        if header.iter().all(|&byte| byte == 0) || &header[257..262] != b"ustar" {
+            return None;
+        }
+        let size = parse_octal(&header[124..136])?;
+        let data = archive.get(BLOCK_SIZE..BLOCK_SIZE + size)?;
+        let padded = size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        self.archive = archive.get(BLOCK_SIZE + padded..)?;
+
+        let name = core::str::from_utf8(until_nul(&header[..100])).ok()?;
+        let name = name.strip_prefix("./").unwrap_or(name);
+        let regular = matches!(header[156], b'0' | 0);
+        Some(regular.then(|| File { name, data }))
+    }
+}
+
+fn until_nul(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+/// Parses a numeric header field, octal digits ended by a NUL or a space.
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let digits = core::str::from_utf8(until_nul(field)).ok()?.trim();
+    usize::from_str_radix(digits, 8).ok()
+}
+
+#[test_case]
+fn test_parse_octal() {
+    assert_eq!(parse_octal(b"00000020440\0"), Some(8480));
+    assert_eq!(parse_octal(b"0000644 \0"), Some(0o644));
+    assert_eq!(parse_octal(b"12x\0"), None);
+}
@@ -1,36 +1,134 @@
+use core::arch::global_asm;
 use x86_64::structures::idt::{
     InterruptDescriptorTable,
     InterruptStackFrame,
     PageFaultErrorCode
 };
-use crate::{println, eprintln, gdt, hlt_loop, time, task::sleep};
+use x86_64::{PrivilegeLevel, VirtAddr};
+use crate::{println, eprintln, gdt, hlt_loop, time, process::{self, Fault}, syscall, apic, spinlock::SpinLock};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
-use core::time::Duration;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
         unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
+            idt.breakpoint.set_handler_addr(entry(breakpoint_entry));
+            idt.divide_error.set_handler_addr(entry(divide_error_entry));
+            idt.invalid_opcode.set_handler_addr(entry(invalid_opcode_entry));
+            idt.stack_segment_fault.set_handler_addr(entry(stack_segment_fault_entry));
+            idt.general_protection_fault.set_handler_addr(entry(general_protection_fault_entry));
+            idt.page_fault.set_handler_addr(entry(page_fault_entry));
+            idt.double_fault.set_handler_addr(entry(double_fault_entry))
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
+            idt[syscall::SYSCALL_VECTOR as usize].set_handler_addr(syscall::interrupt_entry())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+            idt[apic::SPURIOUS_INTERRUPT_VECTOR as usize]
+                .set_handler_addr(entry(spurious_interrupt_entry));
+            idt[apic::WAKE_UP_VECTOR as usize]
+                .set_handler_addr(entry(wake_up_interrupt_entry));
+            idt[InterruptIndex::Timer.as_usize()]
+                .set_handler_addr(entry(timer_interrupt_entry));
+            idt[InterruptIndex::Keyboard.as_usize()]
+                .set_handler_addr(entry(keyboard_interrupt_entry));
+            idt[InterruptIndex::RealTimeClock.as_usize()]
+                .set_handler_addr(entry(rtc_interrupt_entry));
 
-        #[cfg(feature="mouse")]
-        idt[InterruptIndex::Mouse.as_usize()]
-            .set_handler_fn(mouse_interrupt_handler);
+            #[cfg(feature="mouse")]
+            idt[InterruptIndex::Mouse.as_usize()]
+                .set_handler_addr(entry(mouse_interrupt_entry));
+        }
 
         idt
     };
 }
 
+// Every vector enters through a stub, which swaps the GS base back to the
+// kernel's when coming from user mode, see `percpu`, and swaps it again on
+// the way back. The handlers are plain functions getting the interrupt stack
+// frame and the error code, 0 for vectors without one, so they may also not
+// return, like `process::exit_current` does. The kernel is built without SSE,
+// the general purpose registers are all there is to save.
+macro_rules! interrupt_entry {
+    ($name:literal, $error_code:literal) => {
+        concat!(".global ", $name, "\n", $name, ":\n", ".if ", $error_code, " == 0\n", r#"
+    push 0
+.endif
+    test byte ptr [rsp + 16], 3
+    jz 2f
+    swapgs
+2:
+    push rax
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+    lea rdi, [rsp + 80]
+    mov rsi, [rsp + 72]
+    cld
+    // the CPU aligned the stack before pushing the 5 words of the frame
+    sub rsp, 8
+    call {handler}
+    add rsp, 8
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rax
+    test byte ptr [rsp + 16], 3
+    jz 3f
+    swapgs
+3:
+    add rsp, 8
+    iretq
+"#)
+    };
+}
+
+global_asm!(interrupt_entry!("breakpoint_entry", 0), handler = sym breakpoint_handler);
+global_asm!(interrupt_entry!("divide_error_entry", 0), handler = sym divide_error_handler);
+global_asm!(interrupt_entry!("invalid_opcode_entry", 0), handler = sym invalid_opcode_handler);
+global_asm!(interrupt_entry!("stack_segment_fault_entry", 1), handler = sym stack_segment_fault_handler);
+global_asm!(interrupt_entry!("general_protection_fault_entry", 1), handler = sym general_protection_fault_handler);
+global_asm!(interrupt_entry!("page_fault_entry", 1), handler = sym page_fault_handler);
+global_asm!(interrupt_entry!("double_fault_entry", 1), handler = sym double_fault_handler);
+global_asm!(interrupt_entry!("spurious_interrupt_entry", 0), handler = sym spurious_interrupt_handler);
+global_asm!(interrupt_entry!("wake_up_interrupt_entry", 0), handler = sym wake_up_interrupt_handler);
+global_asm!(interrupt_entry!("timer_interrupt_entry", 0), handler = sym timer_interrupt_handler);
+global_asm!(interrupt_entry!("keyboard_interrupt_entry", 0), handler = sym keyboard_interrupt_handler);
+global_asm!(interrupt_entry!("rtc_interrupt_entry", 0), handler = sym rtc_interrupt_handler);
+#[cfg(feature="mouse")]
+global_asm!(interrupt_entry!("mouse_interrupt_entry", 0), handler = sym mouse_interrupt_handler);
+
+extern "C" {
+    fn breakpoint_entry();
+    fn divide_error_entry();
+    fn invalid_opcode_entry();
+    fn stack_segment_fault_entry();
+    fn general_protection_fault_entry();
+    fn page_fault_entry();
+    fn double_fault_entry();
+    fn spurious_interrupt_entry();
+    fn wake_up_interrupt_entry();
+    fn timer_interrupt_entry();
+    fn keyboard_interrupt_entry();
+    fn rtc_interrupt_entry();
+    #[cfg(feature="mouse")]
+    fn mouse_interrupt_entry();
+}
+
+fn entry(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as u64)
+}
+
 pub fn init_idt() {
     IDT.load();
 }
@@ -38,8 +136,8 @@ pub fn init_idt() {
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: SpinLock<ChainedPics> =
+    SpinLock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -62,6 +160,21 @@ pub enum InterruptIndex {
     SecondaryATA,
 }
 
+/// Unmasks the interrupt line on the PIC it's wired to, along with the
+/// cascade line if it belongs to the secondary PIC.
+pub fn unmask_irq(index: InterruptIndex) {
+    let irq = index.as_u8() - PIC_1_OFFSET;
+    let mut pics = PICS.lock();
+    let [mut mask1, mut mask2] = unsafe { pics.read_masks() };
+    if irq < 8 {
+        mask1 &= !(1 << irq);
+    } else {
+        mask1 &= !(1 << (InterruptIndex::Cascade.as_u8() - PIC_1_OFFSET));
+        mask2 &= !(1 << (irq - 8));
+    }
+    unsafe { pics.write_masks(mask1, mask2) };
+}
+
 impl InterruptIndex {
     fn as_u8(self) -> u8 {
         self as u8
@@ -72,35 +185,71 @@ impl InterruptIndex {
     }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: InterruptStackFrame)
+extern "C" fn breakpoint_handler(
+    stack_frame: &InterruptStackFrame)
 {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64) -> !
+extern "C" fn divide_error_handler(
+    stack_frame: &InterruptStackFrame)
 {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    process::kill_on_user_fault(stack_frame, Fault::DivideError);
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "C" fn invalid_opcode_handler(
+    stack_frame: &InterruptStackFrame)
+{
+    process::kill_on_user_fault(stack_frame, Fault::InvalidOpcode);
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "C" fn stack_segment_fault_handler(
+    stack_frame: &InterruptStackFrame, error_code: u64)
+{
+    process::kill_on_user_fault(stack_frame, Fault::StackSegmentFault);
+    panic!("EXCEPTION: STACK SEGMENT FAULT ({:#x})\n{:#?}", error_code, stack_frame);
+}
+
+extern "C" fn general_protection_fault_handler(
+    stack_frame: &InterruptStackFrame, error_code: u64)
+{
+    process::kill_on_user_fault(stack_frame, Fault::GeneralProtectionFault);
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}", error_code, stack_frame);
 }
 
-const PIT_RATE: Duration = Duration::from_nanos(54_925_400);
+extern "C" fn double_fault_handler(
+    stack_frame: &InterruptStackFrame, _error_code: u64) -> !
+{
+    use x86_64::registers::control::Cr2;
+
+    // the page fault of a guard page can't be handled on the overflowed stack
+    if crate::thread::is_stack_guard_page(Cr2::read()) {
+        panic!("EXCEPTION: DOUBLE FAULT, thread stack overflow at {:?}\n{:#?}", Cr2::read(), stack_frame);
+    }
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+}
 
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+extern "C" fn timer_interrupt_handler(
+    stack_frame: &InterruptStackFrame)
 {
-    time::system_clock_tick(PIT_RATE);
-    sleep::sleep_task_tick(PIT_RATE);
+    time::system_clock_tick();
+    crate::task::sleep::wake_expired_sleepers();
+    crate::task::watchdog::check(stack_frame);
     // log::trace!("Uptime={:?}", time::get_system_uptime());
 
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+
+    process::exit_if_interrupted(stack_frame);
+    crate::thread::preempt();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+extern "C" fn keyboard_interrupt_handler(
+    _stack_frame: &InterruptStackFrame)
 {
     // log::trace!("keyboard interrupt");
 
@@ -116,9 +265,34 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     }
 }
 
+extern "C" fn rtc_interrupt_handler(
+    _stack_frame: &InterruptStackFrame)
+{
+    crate::rtc::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::RealTimeClock.as_u8());
+    }
+}
+
+/// Raised by the local APIC when an interrupt went away before it could be
+/// delivered, it takes no EOI.
+extern "C" fn spurious_interrupt_handler(
+    _stack_frame: &InterruptStackFrame)
+{
+}
+
+/// Only there to end a `hlt`, the woken executor finds its queued tasks.
+extern "C" fn wake_up_interrupt_handler(
+    _stack_frame: &InterruptStackFrame)
+{
+    apic::end_of_interrupt();
+}
+
 #[cfg(feature="mouse")]
-extern "x86-interrupt" fn mouse_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+extern "C" fn mouse_interrupt_handler(
+    _stack_frame: &InterruptStackFrame)
 {
     // log::trace!("mouse interrupt");
 
@@ -134,12 +308,18 @@ extern "x86-interrupt" fn mouse_interrupt_handler(
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
+extern "C" fn page_fault_handler(
+    stack_frame: &InterruptStackFrame,
+    error_code: u64,
 ) {
     use x86_64::registers::control::Cr2;
 
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    if stack_frame.code_segment & 0b11 == 3 {
+        log::warn!("user page fault accessing {:?}: {:?}", Cr2::read(), error_code);
+    }
+    process::kill_on_user_fault(stack_frame, Fault::PageFault);
+
     eprintln!("EXCEPTION: PAGE FAULT");
     eprintln!("Accessed Address: {:?}", Cr2::read());
     eprintln!("Error Code: {:?}", error_code);
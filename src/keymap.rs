@@ -0,0 +1,274 @@
+//! Keyboard layouts, switchable at runtime with the `keymap` command.
+//!
+//! The US, UK and German layouts come from `pc_keyboard`, ABNT2 is defined
+//! here on top of the US one. Only characters `encoding::utf16_to_cp437` can
+//! show are produced, dead keys compose with the next character when the
+//! result is one of them and are typed on their own otherwise.
+
+use core::{fmt, str::FromStr, sync::atomic::{AtomicU8, Ordering}};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyboardLayout, Modifiers};
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Keymap {
+    Us,
+    Uk,
+    De,
+    Abnt2,
+}
+
+static CURRENT: AtomicU8 = AtomicU8::new(Keymap::Us as u8);
+
+impl Keymap {
+    pub const ALL: [Keymap; 4] = [Keymap::Us, Keymap::Uk, Keymap::De, Keymap::Abnt2];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Keymap::Us => "us",
+            Keymap::Uk => "uk",
+            Keymap::De => "de",
+            Keymap::Abnt2 => "abnt2",
+        }
+    }
+
+    /// Whether `character` is typed by a dead key, which waits for the next
+    /// one instead of being typed.
+    pub fn is_dead_key(self, character: char) -> bool {
+        match self {
+            Keymap::Us | Keymap::Uk => false,
+            Keymap::De => matches!(character, '^' | '´' | '`'),
+            Keymap::Abnt2 => matches!(character, '´' | '`' | '~' | '^' | '¨'),
+        }
+    }
+
+    /// Character of a key scancode set 1 of `pc_keyboard` has no key code
+    /// for: the one next to the left shift (0x56) and the one next to the
+    /// right shift on ABNT2 keyboards (0x73).
+    fn map_extra_key(self, scancode: u8, shifted: bool, alt_gr: bool) -> Option<char> {
+        match (self, scancode) {
+            (Keymap::De, 0x56) if alt_gr => Some('|'),
+            (Keymap::De, 0x56) => Some(if shifted { '>' } else { '<' }),
+            (_, 0x56) => Some(if shifted { '|' } else { '\\' }),
+            (Keymap::Abnt2, 0x73) => Some(if shifted { '?' } else { '/' }),
+            _ => None
+        }
+    }
+}
+
+impl FromStr for Keymap {
+    type Err = Error;
+
+    fn from_str(keymap: &str) -> Result<Self, Self::Err> {
+        Keymap::ALL.into_iter()
+            .find(|candidate| candidate.name().eq_ignore_ascii_case(keymap))
+            .ok_or(Error::UnknownKeymap)
+    }
+}
+
+impl fmt::Display for Keymap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub fn current() -> Keymap {
+    Keymap::ALL[CURRENT.load(Ordering::Relaxed) as usize]
+}
+
+/// Switches the layout used for the following key presses.
+pub fn set(keymap: Keymap) {
+    CURRENT.store(keymap as u8, Ordering::Relaxed);
+    log::info!("keymap: {}", keymap);
+}
+
+/// Layout for `pc_keyboard::Keyboard` following the current keymap.
+pub struct CurrentLayout;
+
+impl KeyboardLayout for CurrentLayout {
+    fn map_keycode(keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        match current() {
+            Keymap::Us => layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Keymap::Uk => layouts::Uk105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Keymap::De => layouts::De104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Keymap::Abnt2 => map_abnt2(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+/// Brazilian ABNT2, the keys it shares with US 104 are left to that layout.
+fn map_abnt2(keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+    let shifted = modifiers.is_shifted();
+    let alt_gr = modifiers.alt_gr;
+    let character = match keycode {
+        KeyCode::BackTick => if shifted { '"' } else { '\'' },
+        KeyCode::Key2 if alt_gr => '²',
+        KeyCode::Key4 if alt_gr => '£',
+        KeyCode::Key5 if alt_gr => '¢',
+        KeyCode::Key6 if alt_gr => '¬',
+        KeyCode::Key6 if shifted => '¨',
+        KeyCode::Equals if alt_gr => '§',
+        KeyCode::Q if alt_gr => '/',
+        KeyCode::W if alt_gr => '?',
+        KeyCode::E if alt_gr => '°',
+        KeyCode::BracketSquareLeft => if shifted { '`' } else { '´' },
+        KeyCode::BracketSquareRight if alt_gr => 'ª',
+        KeyCode::BracketSquareRight => if shifted { '{' } else { '[' },
+        KeyCode::SemiColon => if modifiers.is_caps() { 'Ç' } else { 'ç' },
+        KeyCode::Quote => if shifted { '^' } else { '~' },
+        KeyCode::BackSlash if alt_gr => 'º',
+        KeyCode::BackSlash => if shifted { '}' } else { ']' },
+        KeyCode::Slash => if shifted { ':' } else { ';' },
+        _ => return layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl)
+    };
+    DecodedKey::Unicode(character)
+}
+
+/// Decodes the keys `Keymap::map_extra_key` covers, which have to be taken
+/// out before the scancodes reach `pc_keyboard`.
+#[derive(Debug, Default)]
+pub struct ExtraKeys {
+    extended: bool,
+    left_shift: bool,
+    right_shift: bool,
+    alt_gr: bool,
+}
+
+impl ExtraKeys {
+    pub const fn new() -> Self {
+        ExtraKeys { extended: false, left_shift: false, right_shift: false, alt_gr: false }
+    }
+
+    /// Returns `Some` for the scancode of an extra key, with the character
+    /// it types when pressed, and `None` for scancodes to pass on.
+    pub fn add_byte(&mut self, scancode: u8) -> Option<Option<char>> {
+        let extended = core::mem::replace(&mut self.extended, scancode == 0xE0);
+        let pressed = scancode & 0x80 == 0;
+        match (extended, scancode & 0x7F) {
+            (false, 0x2A) => self.left_shift = pressed,
+            (false, 0x36) => self.right_shift = pressed,
+            (true, 0x38) => self.alt_gr = pressed,
+            (false, code) => {
+                let shifted = self.left_shift || self.right_shift;
+                if let Some(character) = current().map_extra_key(code, shifted, self.alt_gr) {
+                    return Some(pressed.then_some(character));
+                }
+            },
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Holds back the character of a dead key until the next one is typed.
+#[derive(Debug, Default)]
+pub struct DeadKeys {
+    pending: Option<char>,
+}
+
+impl DeadKeys {
+    pub const fn new() -> Self {
+        DeadKeys { pending: None }
+    }
+
+    /// Feeds a typed character through, returning the characters to type
+    /// in its place.
+    pub fn compose(&mut self, keymap: Keymap, character: char) -> impl Iterator<Item = char> {
+        let (first, second) = match self.pending.take() {
+            None if keymap.is_dead_key(character) => {
+                self.pending = Some(character);
+                (None, None)
+            },
+            None => (Some(character), None),
+            // backspace, enter and the like drop the accent
+            Some(_) if character.is_control() => (Some(character), None),
+            Some(dead) => match compose(dead, character) {
+                Some(composed) => (Some(composed), None),
+                None if character == ' ' || character == dead => (Some(spacing(dead)), None),
+                None if keymap.is_dead_key(character) => {
+                    self.pending = Some(character);
+                    (Some(spacing(dead)), None)
+                },
+                None => (Some(spacing(dead)), Some(character)),
+            }
+        };
+        first.into_iter().chain(second)
+    }
+}
+
+/// Character typed for a dead key on its own, ´ and ¨ aren't in code page
+/// 437 so they fall back to the closest ASCII.
+fn spacing(dead: char) -> char {
+    match dead {
+        '´' => '\'',
+        '¨' => '"',
+        _ => dead
+    }
+}
+
+/// Accented letters code page 437 has.
+fn compose(dead: char, character: char) -> Option<char> {
+    let composed = match (dead, character) {
+        ('´', 'a') => 'á',
+        ('´', 'e') => 'é',
+        ('´', 'E') => 'É',
+        ('´', 'i') => 'í',
+        ('´', 'o') => 'ó',
+        ('´', 'u') => 'ú',
+        ('`', 'a') => 'à',
+        ('`', 'e') => 'è',
+        ('`', 'i') => 'ì',
+        ('`', 'o') => 'ò',
+        ('`', 'u') => 'ù',
+        ('^', 'a') => 'â',
+        ('^', 'e') => 'ê',
+        ('^', 'i') => 'î',
+        ('^', 'o') => 'ô',
+        ('^', 'u') => 'û',
+        ('~', 'n') => 'ñ',
+        ('~', 'N') => 'Ñ',
+        ('¨', 'a') => 'ä',
+        ('¨', 'A') => 'Ä',
+        ('¨', 'e') => 'ë',
+        ('¨', 'i') => 'ï',
+        ('¨', 'o') => 'ö',
+        ('¨', 'O') => 'Ö',
+        ('¨', 'u') => 'ü',
+        ('¨', 'U') => 'Ü',
+        ('¨', 'y') => 'ÿ',
+        _ => return None
+    };
+    Some(composed)
+}
+
+#[test_case]
+fn test_dead_keys_compose() {
+    use alloc::string::String;
+
+    let mut dead_keys = DeadKeys::new();
+    let mut typed = String::new();
+    for character in "´e~n^x´ ".chars() {
+        typed.extend(dead_keys.compose(Keymap::Abnt2, character));
+    }
+    assert_eq!(typed, "éñ^x'");
+    // not a dead key on US keyboards
+    assert_eq!(dead_keys.compose(Keymap::Us, '^').next(), Some('^'));
+}
+
+#[test_case]
+fn test_composed_characters_are_displayable() {
+    for dead in ['´', '`', '^', '~', '¨'] {
+        assert_ne!(crate::encoding::utf16_to_cp437(spacing(dead) as u16), 0xfe);
+        for character in ('A'..='Z').chain('a'..='z') {
+            if let Some(composed) = compose(dead, character) {
+                assert_ne!(crate::encoding::utf16_to_cp437(composed as u16), 0xfe, "{}", composed);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_keymap_from_str() {
+    assert_eq!("ABNT2".parse::<Keymap>().ok(), Some(Keymap::Abnt2));
+    assert!("dvorak".parse::<Keymap>().is_err());
+}
@@ -2,7 +2,6 @@
 #![cfg_attr(test, no_main)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
-#![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
 #![feature(const_mut_refs)]
 #![feature(custom_test_frameworks)]
@@ -17,13 +16,28 @@ pub mod gdt;
 pub mod memory;
 pub mod allocator;
 pub mod task;
+pub mod thread;
+pub mod process;
+pub mod syscall;
+pub mod console;
+pub mod clipboard;
+pub mod initrd;
+pub mod apic;
+pub mod smp;
+pub mod spinlock;
 pub mod time;
+pub mod pit;
+pub mod ps2;
+pub mod rtc;
 pub mod command;
 pub mod logging;
 pub mod encoding;
+pub mod keymap;
 pub mod error;
 #[cfg(feature="acpi-feat")]
 pub mod acpi;
+#[cfg(feature="acpi-feat")]
+pub mod hpet;
 
 #[cfg(feature="pc-speaker")]
 pub mod pc_speaker;
@@ -39,20 +53,35 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo xtest`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
     logging::init(log::LevelFilter::Trace).unwrap();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
     init();
     test_main();
     hlt_loop()
 }
 
 pub fn init() {
-    #[cfg(feature="mouse")]
-    task::mouse::init();
-
+    vga_buffer::init();
     gdt::init();
+    smp::percpu::init_bootstrap();
+    syscall::init();
     interrupts::init_idt();
+    #[cfg(feature="acpi-feat")]
+    hpet::init();
+    time::init();
     unsafe { interrupts::PICS.lock().initialize() };
+    ps2::init();
+    rtc::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -12,7 +12,10 @@ impl log::Log for SerialLogger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            serial_println!("{}:{}: {} - {}",
+            let uptime = crate::time::get_system_uptime();
+            serial_println!("[{:>5}.{:06}] {}:{}: {} - {}",
+                uptime.as_secs(),
+                uptime.subsec_micros(),
                 record.module_path().unwrap_or("_"),
                 record.line().unwrap_or(0),
                 record.level(),
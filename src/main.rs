@@ -10,11 +10,14 @@ use core::panic::PanicInfo;
 use bootloader::{BootInfo, entry_point};
 use blog_os::{
     println,
+    command,
     vga_buffer::{self, Color},
     memory::{self, BootInfoFrameAllocator},
     allocator,
     logging,
-    task::{Task, executor::Executor, keyboard, spawner}
+    smp,
+    thread,
+    task::{Priority, executor::Executor, keyboard, spawner}
 };
 use x86_64::VirtAddr;
 use log::LevelFilter;
@@ -41,6 +44,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     allocator::init_heap(&mut mapper, &mut frame_allocator)
     .expect("heap initialization failed");
+    memory::init_mapper(mapper, frame_allocator);
 
     #[cfg(feature="acpi-feat")]
     match acpi::init_acpi_info(boot_info.physical_memory_offset, false) {
@@ -49,22 +53,27 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     }
 
     blog_os::init();
+    thread::init();
+    smp::init();
 
     #[cfg(test)]
     test_main();
 
+    // the executor runs on the main thread, alongside the kernel threads
     log::trace!("initializing task execution");
     let mut executor = Executor::new();
 
-    spawner::spawn(Task::new(async {
+    spawner::spawn_named("init", Priority::Interactive, async {
         #[cfg(feature="random")]
         vga_buffer::randomize_vga_buffer().await;
 
-        spawner::spawn(Task::new(keyboard::print_keypresses()));
+        spawner::spawn_named("shell", Priority::Interactive, command::run_shell());
+        spawner::spawn_named("keyboard", Priority::BottomHalf, keyboard::process_scancodes());
 
         #[cfg(feature="mouse")]
-        //TODO mouse panics if keyboard key is pressed before "beep"
-        spawner::spawn(Task::new(blog_os::task::mouse::process_packets()));
+        spawner::spawn_named("mouse", Priority::BottomHalf, blog_os::task::mouse::process_packets());
+        #[cfg(feature="mouse")]
+        spawner::spawn_named("pointer", Priority::Interactive, blog_os::task::pointer::track_pointer());
 
         #[cfg(feature="pc-speaker")]
         blog_os::pc_speaker::beep();
@@ -76,7 +85,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         println!("╚══════════════════════════════════════════════════════════════════════════════╛");
         vga_buffer::set_color(Color::LightGray, Color::Black);
         println!("Type 'help' to see list of commands.");
-    }));
+    });
     executor.run();
 }
 
@@ -1,10 +1,16 @@
 use x86_64::{
     structures::paging::{
+        mapper::MapToError,
+        page::PageRange,
+        Page,
         PhysFrame,
         Size4KiB,
         FrameAllocator,
+        FrameDeallocator,
+        Mapper,
         OffsetPageTable,
-        PageTable
+        PageTable,
+        PageTableFlags
     },
     VirtAddr,
     PhysAddr,
@@ -13,6 +19,16 @@ use bootloader::bootinfo::{
     MemoryMap,
     MemoryRegionType
 };
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::Cr3;
+use crate::spinlock::SpinLock;
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Physical address of the level 4 table set up by the bootloader.
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+/// Page table mapper and frame allocator, kept after boot to map memory on demand.
+static MAPPER: SpinLock<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = SpinLock::new(None);
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -21,10 +37,120 @@ use bootloader::bootinfo::{
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_PAGE_TABLE.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Virtual address where the complete physical memory is mapped.
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Keeps the boot mapper and frame allocator for `map_pages`.
+///
+/// Must be called once the heap has been mapped with them.
+pub fn init_mapper(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *MAPPER.lock() = Some((mapper, frame_allocator));
+}
+
+/// Maps `pages` to newly allocated frames.
+pub fn map_pages(pages: PageRange<Size4KiB>, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let mut mapper = MAPPER.lock();
+    let (mapper, frame_allocator) = mapper.as_mut().expect("memory mapper not initialized");
+    for page in pages {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+    }
+    Ok(())
+}
+
+/// Maps `frame` at the same virtual address, for code that runs before
+/// paging is enabled. Returns whether it was mapped by this call, it may
+/// already have been by the bootloader.
+pub fn identity_map(frame: PhysFrame, flags: PageTableFlags) -> Result<bool, MapToError<Size4KiB>> {
+    let mut mapper = MAPPER.lock();
+    let (mapper, frame_allocator) = mapper.as_mut().expect("memory mapper not initialized");
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(true)
+        },
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => Ok(false),
+        Err(err) => Err(err)
+    }
+}
+
+/// Removes a mapping made by `identity_map`, the frame isn't freed.
+pub fn unmap_identity(frame: PhysFrame) {
+    let mut mapper = MAPPER.lock();
+    let (mapper, _) = mapper.as_mut().expect("memory mapper not initialized");
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    match mapper.unmap(page) {
+        Ok((_, flush)) => flush.flush(),
+        Err(err) => log::error!("unmapping {:?} failed: {:?}", page, err)
+    }
+}
+
+/// Takes the frame below 1 MiB set aside for code started in real mode, like
+/// the startup of other CPUs. There is only one.
+pub fn take_low_frame() -> Option<PhysFrame> {
+    with_frame_allocator(|frame_allocator| {
+        if core::mem::replace(&mut frame_allocator.low_frame_taken, true) {
+            return None;
+        }
+        frame_allocator.low_frame
+    })
+}
+
+/// Runs `f` with the frame allocator kept by `init_mapper`.
+pub fn with_frame_allocator<R>(f: impl FnOnce(&mut BootInfoFrameAllocator) -> R) -> R {
+    let mut mapper = MAPPER.lock();
+    let (_, frame_allocator) = mapper.as_mut().expect("memory mapper not initialized");
+    f(frame_allocator)
+}
+
+/// Level 4 table of the kernel, which threads without an address space of
+/// their own run on.
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
+
+/// Makes the level 4 table at `frame` the active one, unless it already is.
+///
+/// This function is unsafe because the caller must guarantee that the table
+/// maps the kernel like the active one.
+pub unsafe fn switch_page_table(frame: PhysFrame) {
+    let (active, flags) = Cr3::read();
+    if active != frame {
+        Cr3::write(frame, flags);
+    }
+}
+
+/// Returns a mutable reference to the page table stored in `frame`.
+///
+/// This function is unsafe because the caller must guarantee that `frame`
+/// holds a page table and that no other reference to it exists.
+pub unsafe fn page_table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    let virt = physical_memory_offset() + frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr::<PageTable>()
+}
+
+/// Fills `frame` with zeros.
+///
+/// This function is unsafe because the caller must guarantee that `frame`
+/// isn't in use.
+pub unsafe fn zero_frame(frame: PhysFrame) {
+    let virt = physical_memory_offset() + frame.start_address().as_u64();
+    core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frame.size() as usize);
+}
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -34,8 +160,6 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     -> &'static mut PageTable
 {
-    use x86_64::registers::control::Cr3;
-
     let (level_4_table_frame, _) = Cr3::read();
 
     let phys = level_4_table_frame.start_address();
@@ -49,6 +173,11 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    /// Frames given back, handed out again before new ones.
+    free_frames: Vec<PhysFrame>,
+    /// Usable frame below 1 MiB never allocated, see `take_low_frame`.
+    low_frame: Option<PhysFrame>,
+    low_frame_taken: bool,
 }
 
 impl BootInfoFrameAllocator {
@@ -58,10 +187,17 @@ impl BootInfoFrameAllocator {
     /// memory map is valid. The main requirement is that all frames that are marked
     /// as `USABLE` in it are really unused.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let mut frame_allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
-        }
+            free_frames: Vec::new(),
+            low_frame: None,
+            low_frame_taken: false,
+        };
+        // frame 0 is left alone, a null physical address is an easy mistake
+        frame_allocator.low_frame = frame_allocator.usable_frames()
+            .find(|frame| (0x1000..0x10_0000).contains(&frame.start_address().as_u64()));
+        frame_allocator
     }
 
     /// Returns an iterator over the usable frames specified in the memory map.
@@ -82,8 +218,21 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
+        if let Some(frame) = self.free_frames.pop() {
+            return Some(frame);
+        }
+        let mut frame = self.usable_frames().nth(self.next);
         self.next += 1;
+        if frame.is_some() && frame == self.low_frame {
+            frame = self.usable_frames().nth(self.next);
+            self.next += 1;
+        }
         frame
     }
 }
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free_frames.push(frame);
+    }
+}
@@ -1,11 +1,12 @@
 use x86_64::instructions::port::Port;
 use crate::task::{
+    join::JoinHandle,
     spawner,
-    Task,
-    sleep::Sleep
+    Priority,
+    sleep::Sleep,
+    sync::Mutex
 };
 use core::time::Duration;
-use spin::Mutex;
 
 const PIT_COMMAND_ADDRESS: u16 = 0x43;
 const PIT_CHANNEL2_PORT_ADDRESS: u16 = 0x42;
@@ -60,16 +61,24 @@ impl PCSpeaker {
     }
 
     pub async fn beep(&mut self) {
-        self.play_frequency(750);
+        // stops the sound even when the task is aborted during the sleep
+        struct Silence<'a>(&'a mut PCSpeaker);
+        impl Drop for Silence<'_> {
+            fn drop(&mut self) {
+                self.0.stop();
+            }
+        }
+
+        let speaker = Silence(self);
+        speaker.0.play_frequency(750);
         Sleep::new(Duration::from_millis(55)).await;
-        self.stop();
         //self.set_frequency(old_frequency);
     }
 }
 
-pub fn beep() {
-    //TODO can cause deadlock
-    spawner::spawn(Task::new(async {
-        crate::pc_speaker::PC_SPEAKER.lock().beep().await;
-    }))
+/// Beeps in a task of its own, which the returned handle can abort.
+pub fn beep() -> JoinHandle<()> {
+    spawner::spawn_named("beep", Priority::Interactive, async {
+        PC_SPEAKER.lock().await.beep().await;
+    });
 }
@@ -0,0 +1,59 @@
+use x86_64::instructions::port::Port;
+use core::time::Duration;
+use crate::spinlock::SpinLock;
+
+const PIT_COMMAND_ADDRESS: u16 = 0x43;
+const PIT_CHANNEL0_PORT_ADDRESS: u16 = 0x40;
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// Channel 0, lobyte/hibyte access, binary counting.
+const CHANNEL0_LOBYTE_HIBYTE: u8 = 0b0011_0000;
+const MODE_INTERRUPT_ON_TERMINAL_COUNT: u8 = 0b000 << 1;
+const MODE_SQUARE_WAVE: u8 = 0b011 << 1;
+
+/// Largest count the 16 bit counter can hold, written as zero.
+pub const MAX_COUNT: u32 = 0x10000;
+
+pub static PIT: SpinLock<Pit> = SpinLock::new(Pit::new());
+
+//https://wiki.osdev.org/Programmable_Interval_Timer
+pub struct Pit {
+    command_port: Port<u8>,
+    channel0_port: Port<u8>
+}
+
+impl Pit {
+    const fn new() -> Pit {
+        Pit {
+            command_port: Port::new(PIT_COMMAND_ADDRESS),
+            channel0_port: Port::new(PIT_CHANNEL0_PORT_ADDRESS)
+        }
+    }
+
+    fn program_channel0(&mut self, mode: u8, count: u32) {
+        let count = count.clamp(1, MAX_COUNT);
+        unsafe {
+            self.command_port.write(CHANNEL0_LOBYTE_HIBYTE | mode);
+            self.channel0_port.write(count as u8);
+            self.channel0_port.write((count >> 8) as u8);
+        }
+    }
+
+    /// Interrupts every `count` ticks, which is how the BIOS leaves the PIT
+    /// with the maximum count.
+    pub fn set_periodic(&mut self, count: u32) {
+        self.program_channel0(MODE_SQUARE_WAVE, count);
+    }
+
+    /// Interrupts once after `count` ticks, the counter keeps running
+    /// afterwards without raising more interrupts.
+    pub fn set_one_shot(&mut self, count: u32) {
+        self.program_channel0(MODE_INTERRUPT_ON_TERMINAL_COUNT, count);
+    }
+}
+
+/// Number of PIT ticks in `duration`, saturated to what the counter can hold.
+pub fn duration_to_count(duration: Duration) -> u32 {
+    let count = duration.as_nanos() * PIT_FREQUENCY as u128 / 1_000_000_000;
+    count.min(MAX_COUNT as u128) as u32
+}
@@ -0,0 +1,170 @@
+use core::ops::Range;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, Translate, TranslateResult},
+        FrameAllocator,
+        FrameDeallocator,
+        Mapper,
+        OffsetPageTable,
+        Page,
+        PageTable,
+        PageTableFlags,
+        PhysFrame,
+        Size4KiB
+    },
+    VirtAddr
+};
+use crate::{error::Error, memory};
+
+/// User space, it gets level 4 entries of its own so that every other entry
+/// can be shared with the kernel.
+pub const USER_START: u64 = 0x0000_1000_0000_0000;
+pub const USER_END: u64 = 0x0000_2000_0000_0000;
+const USER_P4_INDICES: Range<usize> = 32..64;
+const PAGE_SIZE: u64 = 4096;
+
+/// Page tables of a process, mapping its user space along with the kernel.
+pub struct AddressSpace {
+    level_4_table: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user space.
+    ///
+    /// The kernel's level 4 entries are copied, so kernel mappings made in
+    /// existing entries later on are shared but new entries aren't.
+    pub fn new() -> Result<AddressSpace, Error> {
+        let level_4_table = memory::with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        let table = unsafe { memory::page_table_mut(level_4_table) };
+        let kernel_table = unsafe { memory::page_table_mut(memory::kernel_page_table()) };
+        table.zero();
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if USER_P4_INDICES.contains(&index) {
+                assert!(entry.is_unused(), "kernel mapping in user space");
+            } else {
+                table[index] = entry.clone();
+            }
+        }
+        Ok(AddressSpace { level_4_table })
+    }
+
+    pub fn level_4_table(&self) -> PhysFrame {
+        self.level_4_table
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(memory::page_table_mut(self.level_4_table), memory::physical_memory_offset()) }
+    }
+
+    /// Whether `len` bytes at `start` are in user space.
+    pub fn is_user_range(start: VirtAddr, len: u64) -> bool {
+        start.as_u64() >= USER_START
+            && start.as_u64().checked_add(len).map_or(false, |end| end <= USER_END)
+    }
+
+    /// Whether `len` bytes at `start` are in user space and mapped for user
+    /// mode, writable as well if `writable` is set.
+    pub fn is_accessible(&mut self, start: VirtAddr, len: u64, writable: bool) -> bool {
+        if !Self::is_user_range(start, len) {
+            return false;
+        }
+        if len == 0 {
+            return true;
+        }
+
+        let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if writable {
+            required |= PageTableFlags::WRITABLE;
+        }
+        let mapper = self.mapper();
+        let mut pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(start + (len - 1))
+        );
+        pages.all(|page| match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags.contains(required),
+            _ => false
+        })
+    }
+
+    /// Maps the pages covering `len` bytes at `start` to zeroed frames
+    /// accessible from user mode.
+    pub fn map(&mut self, start: VirtAddr, len: u64, flags: PageTableFlags) -> Result<(), Error> {
+        assert!(Self::is_user_range(start, len), "mapping outside of user space");
+        if len == 0 {
+            return Ok(());
+        }
+
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(start + (len - 1))
+        );
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapper = self.mapper();
+        memory::with_frame_allocator(|frame_allocator| {
+            for page in pages {
+                let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+                unsafe {
+                    memory::zero_frame(frame);
+                    // not the active table, there is nothing to flush
+                    mapper.map_to_with_table_flags(page, frame, flags, table_flags, frame_allocator)?.ignore();
+                }
+            }
+            Ok(())
+        }).map_err(Error::from)
+    }
+
+    /// Copies `data` to `start`, which must have been mapped with `map`.
+    pub fn write(&mut self, start: VirtAddr, data: &[u8]) {
+        let mapper = self.mapper();
+        let mut written = 0;
+        while written < data.len() {
+            let address = start + written as u64;
+            let physical = mapper.translate_addr(address).expect("writing to unmapped user memory");
+            let chunk = (PAGE_SIZE - address.as_u64() % PAGE_SIZE).min((data.len() - written) as u64) as usize;
+            let destination = (memory::physical_memory_offset() + physical.as_u64()).as_mut_ptr::<u8>();
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[written..].as_ptr(), destination, chunk);
+            }
+            written += chunk;
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the user space frames and page tables, the address space must not
+    /// be active anymore.
+    fn drop(&mut self) {
+        let level_4_table = unsafe { memory::page_table_mut(self.level_4_table) };
+        memory::with_frame_allocator(|frame_allocator| unsafe {
+            for index in USER_P4_INDICES {
+                free_table(&mut level_4_table[index], 3, frame_allocator);
+            }
+            frame_allocator.deallocate_frame(self.level_4_table);
+        });
+    }
+}
+
+/// Frees what `entry` points to, a level `level` table along with everything
+/// mapped under it, or a mapped frame at level 0.
+unsafe fn free_table(
+    entry: &mut x86_64::structures::paging::page_table::PageTableEntry,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>
+) {
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return
+    };
+    if level > 0 {
+        let table: &mut PageTable = memory::page_table_mut(frame);
+        for entry in table.iter_mut() {
+            free_table(entry, level - 1, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+    entry.set_unused();
+}
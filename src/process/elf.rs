@@ -0,0 +1,112 @@
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+use crate::error::Error;
+use super::{AddressSpace, USER_MMAP_START};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PF_W: u32 = 2;
+
+/// Segment of an executable to be loaded into memory.
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    virtual_address: u64,
+    file_size: u64,
+    memory_size: u64,
+}
+
+/// Maps the `PT_LOAD` segments of the ELF64 executable in `data` into
+/// `address_space`, returning its entry point.
+///
+/// Memory past the file contents of a segment, like `.bss`, is zeroed.
+/// Segments must be in user space below `USER_MMAP_START` and not share
+/// pages, which linkers take care of by aligning them to pages.
+pub fn load(data: &[u8], address_space: &mut AddressSpace) -> Result<VirtAddr, Error> {
+    let header = data.get(..HEADER_SIZE).ok_or(Error::InvalidElf("file too short"))?;
+    if &header[0..4] != ELF_MAGIC {
+        return Err(Error::InvalidElf("not an ELF file"));
+    }
+    if header[4] != CLASS_64 || header[5] != LITTLE_ENDIAN {
+        return Err(Error::InvalidElf("not a 64-bit little endian file"));
+    }
+    if read_u16(header, 16) != TYPE_EXECUTABLE {
+        return Err(Error::InvalidElf("not an executable"));
+    }
+    if read_u16(header, 18) != MACHINE_X86_64 {
+        return Err(Error::InvalidElf("not an x86_64 executable"));
+    }
+    let entry = read_u64(header, 24);
+    let program_headers_offset = read_u64(header, 32) as usize;
+    let program_header_size = read_u16(header, 54) as usize;
+    let program_header_count = read_u16(header, 56) as usize;
+    if program_header_size != PROGRAM_HEADER_SIZE {
+        return Err(Error::InvalidElf("unexpected program header size"));
+    }
+
+    let program_headers = program_header_count.checked_mul(PROGRAM_HEADER_SIZE)
+        .and_then(|len| data.get(program_headers_offset..program_headers_offset.checked_add(len)?))
+        .ok_or(Error::InvalidElf("program headers out of bounds"))?;
+    let segments = program_headers.chunks_exact(PROGRAM_HEADER_SIZE)
+        .map(|bytes| ProgramHeader {
+            kind: read_u32(bytes, 0),
+            flags: read_u32(bytes, 4),
+            offset: read_u64(bytes, 8),
+            virtual_address: read_u64(bytes, 16),
+            file_size: read_u64(bytes, 32),
+            memory_size: read_u64(bytes, 40),
+        })
+        .filter(|segment| segment.kind == PT_LOAD);
+
+    for segment in segments {
+        load_segment(data, &segment, address_space)?;
+    }
+    Ok(VirtAddr::new(entry))
+}
+
+fn load_segment(data: &[u8], segment: &ProgramHeader, address_space: &mut AddressSpace) -> Result<(), Error> {
+    if segment.file_size > segment.memory_size {
+        return Err(Error::InvalidElf("segment larger in the file than in memory"));
+    }
+    let contents = usize::try_from(segment.offset).ok()
+        .zip(usize::try_from(segment.file_size).ok())
+        .and_then(|(offset, len)| data.get(offset..offset.checked_add(len)?))
+        .ok_or(Error::InvalidElf("segment out of bounds"))?;
+    let start = VirtAddr::try_new(segment.virtual_address)
+        .map_err(|_| Error::InvalidElf("segment outside of user space"))?;
+    let in_range = AddressSpace::is_user_range(start, segment.memory_size)
+        && segment.virtual_address + segment.memory_size <= USER_MMAP_START;
+    if !in_range {
+        return Err(Error::InvalidElf("segment outside of user space"));
+    }
+
+    // there is no NX support, executable segments are only readable
+    let flags = match segment.flags & PF_W {
+        0 => PageTableFlags::empty(),
+        _ => PageTableFlags::WRITABLE
+    };
+    // frames are zeroed when mapped, which takes care of the rest
+    address_space.map(start, segment.memory_size, flags)?;
+    address_space.write(start, contents);
+    Ok(())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
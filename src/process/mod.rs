@@ -0,0 +1,294 @@
+use alloc::{borrow::Cow, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{fmt, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
+use x86_64::{
+    instructions::interrupts,
+    structures::{idt::InterruptStackFrame, paging::PageTableFlags},
+    VirtAddr
+};
+use crate::{error::Error, gdt, spinlock::SpinLock, thread::{self, JoinHandle, ThreadId}};
+
+pub use address_space::{AddressSpace, USER_START, USER_END};
+
+mod address_space;
+mod elf;
+mod user_mode;
+
+/// Where flat binaries are loaded and started.
+pub const USER_CODE_START: u64 = USER_START;
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+/// Top of the user stack, the last page of user space is left unmapped.
+pub const USER_STACK_TOP: u64 = USER_END - 4096;
+/// How much of the user stack arguments and environment may take up.
+const MAX_ARGUMENTS_SIZE: usize = 16 * 1024;
+/// Where `mmap` starts handing out memory, growing towards the stack.
+pub const USER_MMAP_START: u64 = USER_START + 0x0800_0000_0000;
+
+/// Processes by the thread running them.
+static PROCESSES: SpinLock<BTreeMap<ThreadId, ProcessState>> = SpinLock::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// CPU exception that killed a process, with its vector number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Fault {
+    DivideError = 0,
+    InvalidOpcode = 6,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+}
+
+impl Fault {
+    fn from_vector(vector: u8) -> Fault {
+        match vector {
+            0 => Fault::DivideError,
+            6 => Fault::InvalidOpcode,
+            12 => Fault::StackSegmentFault,
+            13 => Fault::GeneralProtectionFault,
+            14 => Fault::PageFault,
+            _ => unreachable!("no fault with vector {}", vector)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(u32),
+    Killed(Fault),
+    /// Ended by `Process::interrupt`, on Ctrl+C in the shell.
+    Interrupted,
+}
+
+impl ExitStatus {
+    /// Packs the status in the value returned from user mode.
+    fn encode(self) -> u64 {
+        match self {
+            ExitStatus::Exited(code) => code as u64,
+            ExitStatus::Killed(fault) => 1 << 32 | fault as u64,
+            ExitStatus::Interrupted => 2 << 32
+        }
+    }
+
+    fn decode(value: u64) -> ExitStatus {
+        match value >> 32 {
+            0 => ExitStatus::Exited(value as u32),
+            1 => ExitStatus::Killed(Fault::from_vector(value as u8)),
+            _ => ExitStatus::Interrupted
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited with code {}", code),
+            ExitStatus::Killed(fault) => write!(f, "killed by {:?}", fault),
+            ExitStatus::Interrupted => write!(f, "interrupted")
+        }
+    }
+}
+
+/// What the kernel keeps about a running process, see `with_current`.
+pub(crate) struct ProcessState {
+    pub(crate) pid: Pid,
+    pub(crate) address_space: AddressSpace,
+    /// Start of the next `mmap` allocation.
+    pub(crate) mmap_next: u64,
+    interrupted: Arc<AtomicBool>,
+}
+
+/// Handle to a process running in user mode on a thread of its own.
+pub struct Process {
+    pid: Pid,
+    thread: JoinHandle<ExitStatus>,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Ends the process with `ExitStatus::Interrupted` the next time it runs
+    /// in user mode or returns from a syscall, a blocked `read` returns
+    /// early for it.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+        thread::unpark(self.thread.thread_id());
+    }
+
+    /// Blocks until the process has exited.
+    pub fn wait(self) -> ExitStatus {
+        self.thread.join()
+    }
+}
+
+/// Starts a process running `address_space` from `entry`, after mapping its
+/// stack with `args` and `env` on it.
+pub fn spawn(
+    name: impl Into<Cow<'static, str>>,
+    mut address_space: AddressSpace,
+    entry: VirtAddr,
+    args: &[&str],
+    env: &[&str]
+) -> Result<Process, Error> {
+    address_space.map(VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE), USER_STACK_SIZE, PageTableFlags::WRITABLE)?;
+    let stack_pointer = write_start_stack(&mut address_space, args, env)?;
+
+    let pid = Pid::new();
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let thread_interrupted = interrupted.clone();
+    let thread = thread::spawn_named(name, move || {
+        let id = thread::current_id().expect("threads not initialized");
+        let level_4_table = address_space.level_4_table();
+        let state = ProcessState { pid, address_space, mmap_next: USER_MMAP_START, interrupted: thread_interrupted };
+        PROCESSES.lock().insert(id, state);
+
+        unsafe { thread::set_page_table(Some(level_4_table)) };
+        let status = enter_user_mode(entry, stack_pointer);
+        // the address space can only be freed once it's not active
+        unsafe { thread::set_page_table(None) };
+        let state = PROCESSES.lock().remove(&id);
+        drop(state);
+        log::info!("process {} {}", pid.0, status);
+        status
+    });
+    Ok(Process { pid, thread, interrupted })
+}
+
+/// Starts a process from a flat binary, loaded at `USER_CODE_START` and
+/// started at its first byte.
+pub fn spawn_flat(name: impl Into<Cow<'static, str>>, code: &[u8]) -> Result<Process, Error> {
+    let mut address_space = AddressSpace::new()?;
+    address_space.map(VirtAddr::new(USER_CODE_START), code.len() as u64, PageTableFlags::empty())?;
+    address_space.write(VirtAddr::new(USER_CODE_START), code);
+    spawn(name, address_space, VirtAddr::new(USER_CODE_START), &[], &[])
+}
+
+/// Starts a process from an ELF executable.
+pub fn spawn_elf(
+    name: impl Into<Cow<'static, str>>,
+    executable: &[u8],
+    args: &[&str],
+    env: &[&str]
+) -> Result<Process, Error> {
+    let mut address_space = AddressSpace::new()?;
+    let entry = elf::load(executable, &mut address_space)?;
+    spawn(name, address_space, entry, args, env)
+}
+
+/// Lays out the stack the way the System V ABI has it on process entry,
+/// returning the stack pointer: `argc`, the `argv` and `envp` pointers each
+/// ended by a null one, an empty auxiliary vector, then the strings.
+fn write_start_stack(address_space: &mut AddressSpace, args: &[&str], env: &[&str]) -> Result<VirtAddr, Error> {
+    let strings_size: usize = args.iter().chain(env).map(|string| string.len() + 1).sum();
+    let pointers_size = (args.len() + env.len() + 6) * 8;
+    if strings_size + pointers_size + 16 > MAX_ARGUMENTS_SIZE {
+        return Err(Error::ArgumentListTooLong);
+    }
+
+    let mut top = USER_STACK_TOP;
+    let mut words = Vec::with_capacity(args.len() + env.len() + 5);
+    words.push(args.len() as u64);
+    for strings in [args, env] {
+        for string in strings {
+            top -= string.len() as u64 + 1;
+            // the stack is zeroed, which terminates the string
+            address_space.write(VirtAddr::new(top), string.as_bytes());
+            words.push(top);
+        }
+        words.push(0);
+    }
+    // AT_NULL
+    words.extend([0, 0]);
+
+    // the stack pointer must be 16 byte aligned on entry
+    top &= !15;
+    if words.len() % 2 == 1 {
+        top -= 8;
+    }
+    top -= words.len() as u64 * 8;
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    address_space.write(VirtAddr::new(top), &bytes);
+    Ok(VirtAddr::new(top))
+}
+
+/// Calls `f` with the process running on the current thread, `None` if
+/// there is none.
+pub(crate) fn with_current<R>(f: impl FnOnce(&mut ProcessState) -> R) -> Option<R> {
+    let id = thread::current_id()?;
+    PROCESSES.lock().get_mut(&id).map(f)
+}
+
+/// Whether the process running on the current thread was interrupted, false
+/// without one.
+pub(crate) fn is_interrupted() -> bool {
+    with_current(|process| process.interrupted.load(Ordering::Relaxed)).unwrap_or(false)
+}
+
+/// Runs the current thread in user mode until the process exits or faults.
+fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> ExitStatus {
+    let user_rsp = thread::user_rsp_slot();
+    interrupts::disable();
+    let value = unsafe {
+        user_mode::user_mode_enter(
+            user_rsp,
+            entry.as_u64(),
+            stack_top.as_u64(),
+            gdt::user_code_selector().0 as u64,
+            gdt::user_data_selector().0 as u64,
+            gdt::kernel_stack_slot()
+        )
+    };
+    // `user_mode_return` is only called with interrupts disabled
+    unsafe { *user_rsp = 0 };
+    interrupts::enable();
+    ExitStatus::decode(value)
+}
+
+/// Ends the process running on the current thread with `status`.
+///
+/// Must be called with interrupts disabled.
+pub(crate) fn exit_current(status: ExitStatus) -> ! {
+    let kernel_rsp = thread::user_rsp().expect("no process on the current thread");
+    unsafe { user_mode::user_mode_return(kernel_rsp, status.encode()) }
+}
+
+/// Called by the exception handlers
+///
+/// Kills the process running on the current thread if the exception came
+/// from user mode, otherwise returns for the kernel to deal with it.
+pub(crate) fn kill_on_user_fault(stack_frame: &InterruptStackFrame, fault: Fault) {
+    if stack_frame.code_segment & 0b11 != 3 {
+        return;
+    }
+    log::warn!("process killed by {:?} at {:?}", fault, stack_frame.instruction_pointer);
+    exit_current(ExitStatus::Killed(fault));
+}
+
+/// Called by the timer interrupt handler after the end of interrupt
+///
+/// Ends the process running on the current thread if it was interrupted
+/// while in user mode.
+pub(crate) fn exit_if_interrupted(stack_frame: &InterruptStackFrame) {
+    if stack_frame.code_segment & 0b11 == 3 && is_interrupted() {
+        exit_current(ExitStatus::Interrupted);
+    }
+}
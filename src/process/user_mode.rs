@@ -0,0 +1,76 @@
+use core::arch::global_asm;
+
+// `user_mode_enter` saves the callee-saved registers and the kernel stack
+// pointer like a context switch, so that `user_mode_return` can pick up from
+// there once the process is done, whatever it was doing in between. It swaps
+// in the user GS base last, the way back to the kernel swaps it out again.
+// `user_mode_return` is only called from the kernel side of that swap.
+global_asm!(r#"
+.global user_mode_enter
+user_mode_enter:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov [r9], rsp
+
+    push r8
+    push rdx
+    push 0x202
+    push rcx
+    push rsi
+
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    swapgs
+    iretq
+
+.global user_mode_return
+user_mode_return:
+    mov rsp, rdi
+    mov rax, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#);
+
+extern "C" {
+    /// Jumps to `entry` in ring 3 with interrupts enabled, returning the value
+    /// given to `user_mode_return`.
+    ///
+    /// The kernel stack pointer is saved to `kernel_rsp` and to `rsp0`, the
+    /// kernel stack of the TSS, so interrupts from user mode don't overwrite
+    /// the frames of the caller.
+    pub fn user_mode_enter(
+        kernel_rsp: *mut u64,
+        entry: u64,
+        user_rsp: u64,
+        code_selector: u64,
+        data_selector: u64,
+        rsp0: *mut u64
+    ) -> u64;
+
+    /// Abandons the current stack to return `value` from `user_mode_enter`,
+    /// with interrupts left as they are.
+    pub fn user_mode_return(kernel_rsp: u64, value: u64) -> !;
+}
@@ -0,0 +1,362 @@
+//! Driver of the 8042 PS/2 controller, with the keyboard on its first port
+//! and the mouse on the second.
+//!
+//! `init` brings the controller and both devices into a known state before
+//! their interrupts are enabled, setting up the mouse while the keyboard is
+//! disabled so key presses can't get in the way. Afterwards the interrupt
+//! handlers read whatever the devices send, acknowledgements included, so
+//! commands sent with `send` don't wait for them.
+//!
+//! https://wiki.osdev.org/%228042%22_PS/2_Controller
+
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+use crate::spinlock::SpinLock;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+const OUTPUT_FULL: u8 = 1 << 0;
+const INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer came from the second port.
+const OUTPUT_FROM_PORT2: u8 = 1 << 5;
+
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_PORT2: u8 = 0xA7;
+const ENABLE_PORT2: u8 = 0xA8;
+const TEST_PORT2: u8 = 0xA9;
+const TEST_CONTROLLER: u8 = 0xAA;
+const TEST_PORT1: u8 = 0xAB;
+const DISABLE_PORT1: u8 = 0xAD;
+const ENABLE_PORT1: u8 = 0xAE;
+const WRITE_PORT2: u8 = 0xD4;
+
+const CONTROLLER_PASSED: u8 = 0x55;
+const PORT_PASSED: u8 = 0x00;
+
+const PORT1_INTERRUPT: u8 = 1 << 0;
+const PORT2_INTERRUPT: u8 = 1 << 1;
+const PORT2_CLOCK_DISABLED: u8 = 1 << 5;
+const PORT1_TRANSLATION: u8 = 1 << 6;
+
+pub const ACK: u8 = 0xFA;
+pub const RESEND: u8 = 0xFE;
+const SELF_TEST_PASSED: u8 = 0xAA;
+
+pub const SET_LEDS: u8 = 0xED;
+const SET_SCANCODE_SET: u8 = 0xF0;
+const IDENTIFY: u8 = 0xF2;
+const SET_TYPEMATIC: u8 = 0xF3;
+pub const ENABLE_SCANNING: u8 = 0xF4;
+const DISABLE_SCANNING: u8 = 0xF5;
+const RESET: u8 = 0xFF;
+
+pub const LED_SCROLL_LOCK: u8 = 1 << 0;
+pub const LED_NUM_LOCK: u8 = 1 << 1;
+pub const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// Scancode set 2, which the controller translates to the set 1 that
+/// `pc_keyboard` decodes.
+const KEYBOARD_SCANCODE_SET: u8 = 2;
+/// 250 ms until keys repeat, then 30 times a second.
+const KEYBOARD_TYPEMATIC: u8 = 0x00;
+
+/// Status polls before giving up on a byte, each takes about a microsecond.
+const TIMEOUT_POLLS: u32 = 100_000;
+/// Devices take up to about half a second for their self-test.
+const RESET_TIMEOUT_POLLS: u32 = 1_000_000;
+const RETRIES: usize = 3;
+
+static CONTROLLER: SpinLock<Controller> = SpinLock::new(Controller::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Keyboard,
+    Mouse,
+}
+
+/// What a device answers to `IDENTIFY`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Keyboard,
+    /// Standard mouse with three buttons and 3 byte packets.
+    Mouse,
+    /// IntelliMouse with a scroll wheel.
+    WheelMouse,
+    /// IntelliMouse with a scroll wheel and two more buttons.
+    FiveButtonMouse,
+    Unknown(u8),
+}
+
+impl DeviceType {
+    pub fn is_mouse(self) -> bool {
+        matches!(self, DeviceType::Mouse | DeviceType::WheelMouse | DeviceType::FiveButtonMouse)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    Timeout,
+    ControllerTestFailed(u8),
+    PortTestFailed(Device, u8),
+    ResetFailed(Device, u8),
+    /// The device kept asking for the byte to be sent again.
+    Resend(Device),
+}
+
+pub struct Controller {
+    data: Port<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+    devices: [Option<DeviceType>; 2],
+}
+
+impl Controller {
+    const fn new() -> Controller {
+        Controller {
+            data: Port::new(DATA_PORT),
+            status: PortReadOnly::new(STATUS_COMMAND_PORT),
+            command: PortWriteOnly::new(STATUS_COMMAND_PORT),
+            devices: [None; 2],
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.status.read() }
+    }
+
+    fn wait_input_empty(&mut self) -> Result<(), Ps2Error> {
+        for _ in 0..TIMEOUT_POLLS {
+            if self.status() & INPUT_FULL == 0 {
+                return Ok(());
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    /// Waits for a byte from the controller itself, or from `device`. Bytes
+    /// from the other device are dropped.
+    fn read(&mut self, device: Option<Device>, polls: u32) -> Result<u8, Ps2Error> {
+        for _ in 0..polls {
+            let status = self.status();
+            if status & OUTPUT_FULL == 0 {
+                continue;
+            }
+            let byte = unsafe { self.data.read() };
+            let from = if status & OUTPUT_FROM_PORT2 != 0 { Device::Mouse } else { Device::Keyboard };
+            if device.map_or(true, |device| device == from) {
+                return Ok(byte);
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn flush(&mut self) {
+        for _ in 0..TIMEOUT_POLLS {
+            if self.status() & OUTPUT_FULL == 0 {
+                return;
+            }
+            unsafe { self.data.read() };
+        }
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    fn command_response(&mut self, command: u8) -> Result<u8, Ps2Error> {
+        self.command(command)?;
+        self.read(None, TIMEOUT_POLLS)
+    }
+
+    fn read_config(&mut self) -> Result<u8, Ps2Error> {
+        self.command_response(READ_CONFIG)
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.command(WRITE_CONFIG)?;
+        self.wait_input_empty()?;
+        unsafe { self.data.write(config) };
+        Ok(())
+    }
+
+    /// Sends `byte` to `device`, without waiting for an answer.
+    pub fn write(&mut self, device: Device, byte: u8) -> Result<(), Ps2Error> {
+        if device == Device::Mouse {
+            self.command(WRITE_PORT2)?;
+        }
+        self.wait_input_empty()?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    /// Waits for the next byte from `device`, only before its interrupts
+    /// are enabled.
+    pub fn read_device(&mut self, device: Device) -> Result<u8, Ps2Error> {
+        self.read(Some(device), TIMEOUT_POLLS)
+    }
+
+    /// Sends `byte` to `device` until it's acknowledged, skipping whatever
+    /// the device sent before, like key presses. Only before the interrupts
+    /// of `device` are enabled.
+    pub fn device_command(&mut self, device: Device, byte: u8) -> Result<(), Ps2Error> {
+        for _ in 0..RETRIES {
+            self.write(device, byte)?;
+            loop {
+                match self.read_device(device)? {
+                    ACK => return Ok(()),
+                    RESEND => break,
+                    _ => continue
+                }
+            }
+        }
+        Err(Ps2Error::Resend(device))
+    }
+
+    /// Sends a command taking a data byte, both have to be acknowledged.
+    pub fn device_command_data(&mut self, device: Device, command: u8, data: u8) -> Result<(), Ps2Error> {
+        self.device_command(device, command)?;
+        self.device_command(device, data)
+    }
+
+    /// Resets `device` and finds out what it is, leaving it with scanning
+    /// disabled.
+    fn reset_device(&mut self, device: Device) -> Result<DeviceType, Ps2Error> {
+        self.device_command(device, RESET)?;
+        match self.read(Some(device), RESET_TIMEOUT_POLLS)? {
+            SELF_TEST_PASSED => {},
+            response => return Err(Ps2Error::ResetFailed(device, response))
+        }
+        // a mouse follows up with its ID, a keyboard may start scanning
+        self.device_command(device, DISABLE_SCANNING)?;
+        self.identify(device)
+    }
+
+    pub fn identify(&mut self, device: Device) -> Result<DeviceType, Ps2Error> {
+        self.device_command(device, IDENTIFY)?;
+        let device_type = match self.read_device(device) {
+            // old AT keyboards don't answer
+            Err(Ps2Error::Timeout) => DeviceType::Keyboard,
+            Err(err) => return Err(err),
+            Ok(0x00) => DeviceType::Mouse,
+            Ok(0x03) => DeviceType::WheelMouse,
+            Ok(0x04) => DeviceType::FiveButtonMouse,
+            Ok(0xAB) => {
+                // the second byte tells MF2 keyboards apart, all work alike
+                let _ = self.read_device(device);
+                DeviceType::Keyboard
+            },
+            Ok(id) => DeviceType::Unknown(id)
+        };
+        Ok(device_type)
+    }
+
+    fn init(&mut self) -> Result<(), Ps2Error> {
+        self.command(DISABLE_PORT1)?;
+        self.command(DISABLE_PORT2)?;
+        self.flush();
+
+        let config = self.read_config()? & !(PORT1_INTERRUPT | PORT2_INTERRUPT | PORT1_TRANSLATION);
+        self.write_config(config)?;
+        match self.command_response(TEST_CONTROLLER)? {
+            CONTROLLER_PASSED => {},
+            response => return Err(Ps2Error::ControllerTestFailed(response))
+        }
+        // the test may have reset the controller
+        self.write_config(config)?;
+
+        // the clock of the second port only starts if there is one
+        self.command(ENABLE_PORT2)?;
+        let dual_port = self.read_config()? & PORT2_CLOCK_DISABLED == 0;
+        self.command(DISABLE_PORT2)?;
+
+        let ports = [
+            (Device::Keyboard, TEST_PORT1, ENABLE_PORT1, true),
+            (Device::Mouse, TEST_PORT2, ENABLE_PORT2, dual_port),
+        ];
+        for (device, test, enable, present) in ports {
+            if !present {
+                continue;
+            }
+            match self.command_response(test)? {
+                PORT_PASSED => {},
+                response => {
+                    log::warn!("{:?}", Ps2Error::PortTestFailed(device, response));
+                    continue;
+                }
+            }
+            self.command(enable)?;
+            match self.reset_device(device) {
+                Ok(device_type) => self.devices[device as usize] = Some(device_type),
+                Err(err) => log::warn!("PS/2 {:?} port: {:?}", device, err)
+            }
+        }
+
+        let keyboard = self.devices[Device::Keyboard as usize] == Some(DeviceType::Keyboard);
+        let mouse = self.devices[Device::Mouse as usize].map_or(false, DeviceType::is_mouse);
+        if keyboard {
+            if let Err(err) = self.configure_keyboard() {
+                log::warn!("PS/2 keyboard setup failed: {:?}", err);
+            }
+        }
+        #[cfg(feature="mouse")]
+        if mouse {
+            // the keyboard stays quiet while the mouse driver talks to its port
+            self.command(DISABLE_PORT1)?;
+            match crate::task::mouse::init(self) {
+                Ok(device_type) => self.devices[Device::Mouse as usize] = Some(device_type),
+                Err(err) => log::warn!("PS/2 mouse setup failed: {:?}", err)
+            }
+            self.command(ENABLE_PORT1)?;
+        }
+
+        let mut config = self.read_config()? | PORT1_TRANSLATION;
+        if keyboard {
+            config |= PORT1_INTERRUPT;
+        }
+        if mouse && cfg!(feature="mouse") {
+            config |= PORT2_INTERRUPT;
+        }
+        self.write_config(config)?;
+        if keyboard {
+            self.device_command(Device::Keyboard, ENABLE_SCANNING)?;
+        }
+        Ok(())
+    }
+
+    fn configure_keyboard(&mut self) -> Result<(), Ps2Error> {
+        self.device_command_data(Device::Keyboard, SET_SCANCODE_SET, KEYBOARD_SCANCODE_SET)?;
+        self.device_command_data(Device::Keyboard, SET_TYPEMATIC, KEYBOARD_TYPEMATIC)?;
+        // num lock starts out on, see `input::Modifiers::new`
+        self.device_command_data(Device::Keyboard, SET_LEDS, LED_NUM_LOCK)
+    }
+}
+
+/// Sets up the controller and the devices on it, must be called with
+/// interrupts disabled.
+pub fn init() {
+    let mut controller = CONTROLLER.lock();
+    match controller.init() {
+        Ok(()) => log::info!("PS/2 keyboard: {:?}, mouse: {:?}", controller.devices[0], controller.devices[1]),
+        Err(err) => log::error!("PS/2 controller initialization failed: {:?}", err)
+    }
+}
+
+/// What `init` found on the port of `device`.
+pub fn device_type(device: Device) -> Option<DeviceType> {
+    CONTROLLER.lock().devices[device as usize]
+}
+
+/// Sends `byte` to `device`, its answer goes to the interrupt handler.
+pub fn send(device: Device, byte: u8) {
+    if let Err(err) = CONTROLLER.lock().write(device, byte) {
+        log::warn!("PS/2 {:?} write failed: {:?}", device, err);
+    }
+}
+
+#[test_case]
+fn test_keyboard_detected() {
+    assert_eq!(device_type(Device::Keyboard), Some(DeviceType::Keyboard));
+}
@@ -0,0 +1,393 @@
+use x86_64::instructions::port::Port;
+use core::{fmt, str::FromStr, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use crate::{error::Error, spinlock::SpinLock, time};
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0A;
+const REGISTER_STATUS_B: u8 = 0x0B;
+const REGISTER_STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_SET: u8 = 1 << 7;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_B_UPDATE_INTERRUPT: u8 = 1 << 4;
+const STATUS_B_BINARY_MODE: u8 = 1 << 2;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_C_PERIODIC_FLAG: u8 = 1 << 6;
+const STATUS_C_UPDATE_FLAG: u8 = 1 << 4;
+const HOUR_PM: u8 = 1 << 7;
+
+/// The RTC only stores two digits of the year.
+const CENTURY: u16 = 2000;
+
+pub static RTC: SpinLock<Rtc> = SpinLock::new(Rtc::new());
+
+/// Wall clock reference: seconds since the unix epoch read from the RTC and
+/// the system uptime at the moment of the reading.
+static WALL_CLOCK: SpinLock<Option<(u64, Duration)>> = SpinLock::new(None);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    fn is_valid(&self) -> bool {
+        self.month >= 1 && self.month <= 12
+            && self.day >= 1 && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Seconds elapsed since 1970-01-01 00:00:00.
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        days * 86_400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let (year, month, day) = civil_from_days(timestamp / 86_400);
+        let seconds_of_day = timestamp % 86_400;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day % 3600 / 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+impl FromStr for DateTime {
+    type Err = Error;
+
+    /// Parses `YYYY-MM-DD HH:MM:SS`.
+    fn from_str(date_time: &str) -> Result<Self, Self::Err> {
+        let (date, time) = date_time.trim().split_once(' ').ok_or(Error::InvalidDateTime)?;
+
+        let mut date = date.split('-').map(|field| field.parse::<u16>());
+        let mut time = time.trim().split(':').map(|field| field.parse::<u8>());
+
+        let mut next_date = || date.next().ok_or(Error::InvalidDateTime)?.map_err(|_| Error::InvalidDateTime);
+        let (year, month, day) = (next_date()?, next_date()?, next_date()?);
+        let mut next_time = || time.next().ok_or(Error::InvalidDateTime)?.map_err(|_| Error::InvalidDateTime);
+        let (hour, minute, second) = (next_time()?, next_time()?, next_time()?);
+
+        let date_time = DateTime {
+            year,
+            month: month as u8,
+            day: day as u8,
+            hour,
+            minute,
+            second,
+        };
+
+        if year >= CENTURY && year < CENTURY + 100 && month <= 12 && day <= 31 && date_time.is_valid() {
+            Ok(date_time)
+        } else {
+            Err(Error::InvalidDateTime)
+        }
+    }
+}
+
+/// Interrupt sources the RTC can raise on IRQ 8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcInterrupt {
+    /// Fires once per second, after the RTC has finished updating its registers.
+    Update,
+    /// Fires at `32768 >> (rate - 1)` Hz, `rate` must be between 3 and 15.
+    Periodic(u8),
+}
+
+//https://wiki.osdev.org/CMOS
+pub struct Rtc {
+    address_port: Port<u8>,
+    data_port: Port<u8>
+}
+
+impl Rtc {
+    const fn new() -> Rtc {
+        Rtc {
+            address_port: Port::new(CMOS_ADDRESS_PORT),
+            data_port: Port::new(CMOS_DATA_PORT)
+        }
+    }
+
+    fn read_register(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.address_port.write(register);
+            self.data_port.read()
+        }
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.address_port.write(register);
+            self.data_port.write(value);
+        }
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read_register(REGISTER_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+    }
+
+    fn read_raw(&mut self) -> [u8; 6] {
+        while self.update_in_progress() {}
+
+        [
+            self.read_register(REGISTER_SECONDS),
+            self.read_register(REGISTER_MINUTES),
+            self.read_register(REGISTER_HOURS),
+            self.read_register(REGISTER_DAY),
+            self.read_register(REGISTER_MONTH),
+            self.read_register(REGISTER_YEAR),
+        ]
+    }
+
+    pub fn read_date_time(&mut self) -> DateTime {
+        // an update may start right after the flag was checked, so read until
+        // two consecutive readings agree
+        let mut raw = self.read_raw();
+        loop {
+            let next = self.read_raw();
+            if next == raw {
+                break;
+            }
+            raw = next;
+        }
+
+        let status_b = self.read_register(REGISTER_STATUS_B);
+        let binary = status_b & STATUS_B_BINARY_MODE != 0;
+        let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+        let [second, minute, hour, day, month, year] = raw;
+        let pm = hour & HOUR_PM != 0;
+        let mut hour = decode(hour & !HOUR_PM);
+        if status_b & STATUS_B_24_HOUR == 0 {
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        DateTime {
+            year: CENTURY + decode(year) as u16,
+            month: decode(month),
+            day: decode(day),
+            hour,
+            minute: decode(minute),
+            second: decode(second),
+        }
+    }
+
+    pub fn set_date_time(&mut self, date_time: &DateTime) {
+        let status_b = self.read_register(REGISTER_STATUS_B);
+        let binary = status_b & STATUS_B_BINARY_MODE != 0;
+        let encode = |value: u8| if binary { value } else { to_bcd(value) };
+
+        let hour = if status_b & STATUS_B_24_HOUR == 0 {
+            let hour_12 = match date_time.hour % 12 {
+                0 => 12,
+                hour => hour
+            };
+            encode(hour_12) | if date_time.hour >= 12 { HOUR_PM } else { 0 }
+        } else {
+            encode(date_time.hour)
+        };
+
+        // stop updates while the registers are written
+        self.write_register(REGISTER_STATUS_B, status_b | STATUS_B_SET);
+        self.write_register(REGISTER_SECONDS, encode(date_time.second));
+        self.write_register(REGISTER_MINUTES, encode(date_time.minute));
+        self.write_register(REGISTER_HOURS, hour);
+        self.write_register(REGISTER_DAY, encode(date_time.day));
+        self.write_register(REGISTER_MONTH, encode(date_time.month));
+        self.write_register(REGISTER_YEAR, encode((date_time.year - CENTURY) as u8));
+        self.write_register(REGISTER_STATUS_B, status_b & !STATUS_B_SET);
+    }
+
+    pub fn enable_interrupt(&mut self, interrupt: RtcInterrupt) {
+        let status_b = self.read_register(REGISTER_STATUS_B);
+        match interrupt {
+            RtcInterrupt::Update => {
+                self.write_register(REGISTER_STATUS_B, status_b | STATUS_B_UPDATE_INTERRUPT);
+            },
+            RtcInterrupt::Periodic(rate) => {
+                let rate = rate.clamp(3, 15);
+                let status_a = self.read_register(REGISTER_STATUS_A);
+                self.write_register(REGISTER_STATUS_A, (status_a & 0xF0) | rate);
+                self.write_register(REGISTER_STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+            }
+        }
+        // interrupts are only raised again after register C is read
+        self.read_register(REGISTER_STATUS_C);
+    }
+
+    pub fn disable_interrupt(&mut self, interrupt: RtcInterrupt) {
+        let status_b = self.read_register(REGISTER_STATUS_B);
+        let flag = match interrupt {
+            RtcInterrupt::Update => STATUS_B_UPDATE_INTERRUPT,
+            RtcInterrupt::Periodic(_) => STATUS_B_PERIODIC_INTERRUPT,
+        };
+        self.write_register(REGISTER_STATUS_B, status_b & !flag);
+    }
+
+    /// Acknowledges the pending interrupt, returning the status C flags.
+    fn acknowledge_interrupt(&mut self) -> u8 {
+        self.read_register(REGISTER_STATUS_C)
+    }
+}
+
+/// Reads the RTC and synchronizes the wall clock with it.
+pub fn init() {
+    sync_wall_clock();
+    log::info!("RTC date/time: {}", now());
+}
+
+fn sync_wall_clock() {
+    // holding on to the RTC keeps the clocks in step
+    let mut rtc = RTC.lock();
+    let date_time = rtc.read_date_time();
+    *WALL_CLOCK.lock() = Some((date_time.to_unix_timestamp(), time::get_system_uptime()));
+}
+
+/// Current wall clock time.
+///
+/// The RTC only has a resolution of one second, so the time is kept by adding
+/// the uptime elapsed since the last synchronization.
+pub fn unix_time() -> Duration {
+    let wall_clock = *WALL_CLOCK.lock();
+    match wall_clock {
+        Some((timestamp, uptime)) => {
+            Duration::from_secs(timestamp) + time::get_system_uptime().saturating_sub(uptime)
+        },
+        None => Duration::from_secs(0)
+    }
+}
+
+pub fn now() -> DateTime {
+    DateTime::from_unix_timestamp(unix_time().as_secs())
+}
+
+pub fn set_date_time(date_time: &DateTime) {
+    let mut rtc = RTC.lock();
+    rtc.set_date_time(date_time);
+    *WALL_CLOCK.lock() = Some((date_time.to_unix_timestamp(), time::get_system_uptime()));
+}
+
+pub fn enable_interrupt(interrupt: RtcInterrupt) {
+    RTC.lock().enable_interrupt(interrupt);
+    crate::interrupts::unmask_irq(crate::interrupts::InterruptIndex::RealTimeClock);
+}
+
+pub fn disable_interrupt(interrupt: RtcInterrupt) {
+    RTC.lock().disable_interrupt(interrupt);
+}
+
+/// Number of periodic interrupts received since they were enabled.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Called by the RTC interrupt handler
+///
+/// Must not block or allocate.
+pub(crate) fn handle_interrupt() {
+    let flags = match RTC.try_lock() {
+        Some(mut rtc) => rtc.acknowledge_interrupt(),
+        None => return
+    };
+
+    if flags & STATUS_C_PERIODIC_FLAG != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    if flags & STATUS_C_UPDATE_FLAG != 0 {
+        // registers are stable for almost a second after the update ended
+        if let (Some(mut rtc), Some(mut wall_clock)) = (RTC.try_lock(), WALL_CLOCK.try_lock()) {
+            let date_time = rtc.read_date_time();
+            *wall_clock = Some((date_time.to_unix_timestamp(), time::get_system_uptime()));
+        }
+    }
+}
+
+const fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+const fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+// http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    let year = year as i64 - if month <= 2 { 1 } else { 0 };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146_097 + day_of_era - 719_468) as u64
+}
+
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as u16, month as u8, day as u8)
+}
+
+#[test_case]
+fn test_unix_timestamp_round_trip() {
+    let date_time: DateTime = "2024-02-29 23:59:58".parse().unwrap();
+    assert_eq!(date_time.to_unix_timestamp(), 1_709_251_198);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_251_198), date_time);
+    assert_eq!(DateTime::from_unix_timestamp(0).to_unix_timestamp(), 0);
+}
+
+#[test_case]
+fn test_bcd() {
+    assert_eq!(from_bcd(0x59), 59);
+    assert_eq!(to_bcd(59), 0x59);
+}
@@ -0,0 +1,68 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::{apic, spinlock::SpinLock, thread::Stack};
+
+pub mod percpu;
+#[cfg(feature="acpi-feat")]
+mod startup;
+#[cfg(feature="acpi-feat")]
+mod trampoline;
+
+/// CPUs beyond this many are left alone.
+pub const MAX_CPUS: usize = 64;
+
+static CPUS: SpinLock<Vec<Cpu>> = SpinLock::new(Vec::new());
+/// CPUs that made it to `ap_main`, counting the bootstrap processor.
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+struct Cpu {
+    apic_id: u32,
+    online: bool,
+    /// `None` for the bootstrap processor, which runs on the boot stack.
+    _stack: Option<Stack>,
+}
+
+/// Point in time view of a CPU, see `cpu_snapshot`.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuSnapshot {
+    /// Index of the CPU, the bootstrap processor is 0.
+    pub id: usize,
+    pub apic_id: u32,
+    /// Whether the CPU started, it is left alone otherwise.
+    pub online: bool,
+}
+
+/// Starts the application processors listed in the ACPI tables, one at a
+/// time, each running its own executor.
+///
+/// Must be called after `acpi::init_acpi_info` and `thread::init`. Without
+/// ACPI only the bootstrap processor is registered.
+pub fn init() {
+    let bootstrap = Cpu { apic_id: 0, online: true, _stack: None };
+    #[cfg(feature="acpi-feat")]
+    let cpus = startup::start_application_processors(bootstrap);
+    #[cfg(not(feature="acpi-feat"))]
+    let cpus = alloc::vec![bootstrap];
+
+    log::info!("{} of {} CPUs online", online_cpus(), cpus.len());
+    *CPUS.lock() = cpus;
+}
+
+/// Number of CPUs running, including the bootstrap processor.
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+/// Lists the CPUs found, including those that didn't start.
+pub fn cpu_snapshot() -> Vec<CpuSnapshot> {
+    CPUS.lock().iter().enumerate()
+        .map(|(id, cpu)| CpuSnapshot { id, apic_id: cpu.apic_id, online: cpu.online })
+        .collect()
+}
+
+/// Sends a wake up IPI to the CPU with `index`, ending its `hlt`.
+pub(crate) fn wake_up(index: usize) {
+    if let Some(cpu) = percpu::get(index) {
+        apic::send_ipi_wake_up(cpu.apic_id());
+    }
+}
@@ -0,0 +1,101 @@
+//! Data private to each CPU, found through its GS base.
+//!
+//! The GS base points to the CPU's `PerCpu`, whose first field points back
+//! to it, so a single `mov` from `gs:0` finds it. User mode has a GS base of
+//! its own, which a process can change by loading GS: every entry from and
+//! exit to user mode runs `swapgs`, so the kernel's sits in `KernelGsBase`
+//! while a process runs and is back in the GS base while the kernel does.
+
+use core::{ptr, sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering}};
+use x86_64::{registers::model_specific::{GsBase, KernelGsBase}, VirtAddr};
+use super::MAX_CPUS;
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Must stay the first field, see `current`. Null until the CPU is set
+    /// up.
+    this: AtomicPtr<PerCpu>,
+    apic_id: AtomicU32,
+}
+
+/// Indexed by CPU, in a static so it can be set up before the heap and
+/// before an application processor takes any lock.
+static CPUS: [PerCpu; MAX_CPUS] = [const { PerCpu { this: AtomicPtr::new(ptr::null_mut()), apic_id: AtomicU32::new(0) } }; MAX_CPUS];
+
+/// Set once the bootstrap processor has its GS base, the only code running
+/// before is its own.
+static BOOTSTRAP_READY: AtomicBool = AtomicBool::new(false);
+
+impl PerCpu {
+    /// Index of the CPU, the bootstrap processor is 0.
+    pub fn index(&self) -> usize {
+        (self as *const PerCpu as usize - CPUS.as_ptr() as usize) / core::mem::size_of::<PerCpu>()
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn is_bootstrap(&self) -> bool {
+        self.index() == 0
+    }
+}
+
+/// Points the GS base of the bootstrap processor to its data.
+///
+/// Called by `crate::init`.
+pub(crate) fn init_bootstrap() {
+    register(0, 0);
+    BOOTSTRAP_READY.store(true, Ordering::Release);
+}
+
+/// Points the GS base of the current application processor to its data.
+///
+/// Must come first on the processor, before it takes any lock.
+pub(crate) fn init(index: usize, apic_id: u32) {
+    assert!(index > 0 && index < MAX_CPUS, "invalid CPU index {}", index);
+    register(index, apic_id);
+}
+
+fn register(index: usize, apic_id: u32) {
+    let data = &CPUS[index];
+    data.apic_id.store(apic_id, Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(data));
+    // swapped in for processes, and for the kernel's data on their way in
+    KernelGsBase::write(VirtAddr::zero());
+    data.this.store(data as *const PerCpu as *mut PerCpu, Ordering::Release);
+}
+
+/// Records the APIC ID of the bootstrap processor, which is only known once
+/// the APIC is set up.
+pub(crate) fn set_bootstrap_apic_id(apic_id: u32) {
+    CPUS[0].apic_id.store(apic_id, Ordering::Relaxed);
+}
+
+/// Data of the CPU running this.
+///
+/// The result is only meaningful while the caller can't migrate, which is
+/// always the case since kernel threads and tasks stay on the CPU polling or
+/// running them until they yield.
+pub fn current() -> &'static PerCpu {
+    let this: *const PerCpu;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags));
+        &*this
+    }
+}
+
+/// Index of the current CPU, which also works during early boot before the
+/// bootstrap processor has its GS base.
+pub fn current_index() -> usize {
+    if BOOTSTRAP_READY.load(Ordering::Acquire) {
+        current().index()
+    } else {
+        0
+    }
+}
+
+/// Data of the CPU with `index`, if it has been set up.
+pub fn get(index: usize) -> Option<&'static PerCpu> {
+    CPUS.get(index).filter(|data| !data.this.load(Ordering::Acquire).is_null())
+}
@@ -0,0 +1,126 @@
+use alloc::{vec, vec::Vec};
+use core::{sync::atomic::Ordering, time::Duration};
+use x86_64::structures::paging::PageTableFlags;
+use crate::{acpi, apic, gdt, interrupts, memory, task::executor::Executor, thread::Stack, time::Instant};
+use super::{percpu, trampoline, Cpu, MAX_CPUS, ONLINE_CPUS};
+
+/// Starts the application processors from the ACPI tables after `bootstrap`,
+/// returning all of them.
+pub(super) fn start_application_processors(mut bootstrap: Cpu) -> Vec<Cpu> {
+    let local_apic_address = match acpi::get_local_apic_address() {
+        Some(local_apic_address) => local_apic_address,
+        None => return vec![bootstrap]
+    };
+    apic::init(local_apic_address);
+    apic::enable();
+    bootstrap.apic_id = apic::id();
+    percpu::set_bootstrap_apic_id(bootstrap.apic_id);
+    let mut cpus = vec![bootstrap];
+
+    let application_processors = acpi::get_application_processors();
+    if application_processors.is_empty() {
+        return cpus;
+    }
+    let frame = match memory::take_low_frame() {
+        Some(frame) => frame,
+        None => {
+            log::error!("no memory below 1 MiB to start application processors from");
+            return cpus;
+        }
+    };
+    let page_table = memory::kernel_page_table().start_address().as_u64();
+    assert!(page_table < 1 << 32, "kernel page table above 4 GiB");
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let newly_mapped = match memory::identity_map(frame, flags) {
+        Ok(newly_mapped) => newly_mapped,
+        Err(err) => {
+            log::error!("mapping application processor trampoline failed: {:?}", err);
+            return cpus;
+        }
+    };
+    let code = trampoline::code();
+    let destination = (memory::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr::<u8>();
+    unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), destination, code.len()) };
+    let parameters = unsafe { destination.add(trampoline::parameters_offset()) as *mut trampoline::Parameters };
+    let page = (frame.start_address().as_u64() / 4096) as u8;
+
+    for &apic_id in application_processors {
+        let index = cpus.len();
+        if index == MAX_CPUS {
+            log::warn!("only using the first {} CPUs", MAX_CPUS);
+            break;
+        }
+        let stack = match Stack::allocate() {
+            Some(stack) => stack,
+            None => {
+                log::error!("no stack for the CPU with APIC ID {}", apic_id);
+                break;
+            }
+        };
+        unsafe {
+            parameters.write_volatile(trampoline::Parameters {
+                page_table,
+                stack_top: stack.top().as_u64(),
+                entry: ap_main as usize as u64,
+                argument: index as u64,
+            });
+        }
+
+        // INIT-SIPI-SIPI, a second startup IPI is ignored by a CPU that
+        // already started
+        let online = ONLINE_CPUS.load(Ordering::Acquire);
+        apic::send_init(apic_id);
+        spin_for(Duration::from_millis(10));
+        for _ in 0..2 {
+            apic::send_startup(apic_id, page);
+            spin_for(Duration::from_micros(200));
+        }
+        let started = wait_for(Duration::from_millis(100), || ONLINE_CPUS.load(Ordering::Acquire) > online);
+        cpus.push(Cpu { apic_id, online: started, _stack: Some(stack) });
+        if !started {
+            // it may still come up, so its parameters and the trampoline
+            // mapping have to stay
+            log::error!("CPU with APIC ID {} didn't start", apic_id);
+            return cpus;
+        }
+    }
+
+    if newly_mapped {
+        memory::unmap_identity(frame);
+    }
+    cpus
+}
+
+fn spin_for(duration: Duration) {
+    wait_for(duration, || false);
+}
+
+/// Spins until `condition` holds or `timeout` passes, returning whether it
+/// held.
+fn wait_for(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    condition()
+}
+
+/// Where application processors continue from the trampoline, on their own
+/// stack with interrupts disabled.
+extern "C" fn ap_main(index: u64) -> ! {
+    percpu::init(index as usize, apic::id());
+    gdt::init_ap();
+    interrupts::init_idt();
+    apic::enable();
+    // ready to take tasks once the CPU counts as online
+    let mut executor = Executor::new();
+    // the trampoline parameters may be reused from here on
+    ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+    log::info!("CPU {} online, APIC ID {}", index, apic::id());
+
+    executor.run()
+}
@@ -0,0 +1,135 @@
+use core::arch::global_asm;
+
+// Copied to a page below 1 MiB, which the startup IPI points the AP to in
+// real mode with CS set to the page's segment. The code only uses offsets
+// from the start, turning them into linear addresses with the base from CS,
+// and the page must be identity mapped for the switch to paging.
+//
+// The parameters at the end are filled in by the BSP before each start.
+global_asm!(r#"
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_trampoline_parameters
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    movzx %ax, %ebx
+    shl $4, %ebx
+
+    lea (ap_gdt - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (ap_gdt_pointer - ap_trampoline_start + 2)
+    lea (ap_protected_mode - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (ap_protected_mode_jump - ap_trampoline_start)
+    lea (ap_long_mode - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (ap_long_mode_jump - ap_trampoline_start)
+
+    lgdtl (ap_gdt_pointer - ap_trampoline_start)
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl *(ap_protected_mode_jump - ap_trampoline_start)
+
+.code32
+ap_protected_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+
+    // PAE
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+    mov (ap_page_table - ap_trampoline_start)(%ebx), %eax
+    mov %eax, %cr3
+    // long mode, and no-execute which the kernel's page tables use
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+    // paging and write protection
+    mov %cr0, %eax
+    or $((1 << 31) | (1 << 16)), %eax
+    mov %eax, %cr0
+    ljmpl *(ap_long_mode_jump - ap_trampoline_start)(%ebx)
+
+.code64
+ap_long_mode:
+    xor %eax, %eax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov (ap_stack_top - ap_trampoline_start)(%rbx), %rsp
+    mov (ap_argument - ap_trampoline_start)(%rbx), %rdi
+    mov (ap_entry - ap_trampoline_start)(%rbx), %rax
+    xor %ebp, %ebp
+    call *%rax
+    ud2
+
+.align 8
+ap_gdt:
+    .quad 0
+    // 32-bit code
+    .quad 0x00cf9a000000ffff
+    // data
+    .quad 0x00cf92000000ffff
+    // 64-bit code
+    .quad 0x00209a0000000000
+ap_gdt_pointer:
+    .word ap_gdt_pointer - ap_gdt - 1
+    .long 0
+ap_protected_mode_jump:
+    .long 0
+    .word 0x08
+ap_long_mode_jump:
+    .long 0
+    .word 0x18
+
+.align 8
+ap_trampoline_parameters:
+ap_page_table:
+    .quad 0
+ap_stack_top:
+    .quad 0
+ap_entry:
+    .quad 0
+ap_argument:
+    .quad 0
+ap_trampoline_end:
+"#, options(att_syntax));
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_parameters: u8;
+}
+
+/// Filled in at the end of the trampoline copy before starting an AP.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Parameters {
+    /// Physical address of the level 4 table, must be below 4 GiB.
+    pub page_table: u64,
+    pub stack_top: u64,
+    /// `extern "C" fn(u64) -> !` called in long mode.
+    pub entry: u64,
+    pub argument: u64,
+}
+
+/// Code the APs start running in real mode.
+pub fn code() -> &'static [u8] {
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let end = &ap_trampoline_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// Offset of `Parameters` in `code`.
+pub fn parameters_offset() -> usize {
+    unsafe { &ap_trampoline_parameters as *const u8 as usize - &ap_trampoline_start as *const u8 as usize }
+}
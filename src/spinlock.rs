@@ -0,0 +1,230 @@
+//! Spinlock for data shared with interrupt handlers or other CPUs.
+//!
+//! Interrupts are disabled while a `SpinLock` is held, so an interrupt handler
+//! can't spin on a lock held by the code it interrupted. Each lock remembers
+//! the CPU and source location holding it. In debug builds taking a lock
+//! again on the CPU holding it, or spinning on it for longer than
+//! `DEADLOCK_THRESHOLD`, panics with the holder's location instead of hanging.
+//!
+//! Locks held for long or across `await`s, like the one of the shell, stay
+//! `spin::Mutex`es or `task::sync::Mutex`es.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+    time::Duration
+};
+use x86_64::instructions::interrupts;
+use crate::smp::percpu;
+
+/// How long a lock may be spun on before debug builds call it a deadlock.
+pub const DEADLOCK_THRESHOLD: Duration = Duration::from_secs(2);
+
+const NO_HOLDER: usize = usize::MAX;
+
+/// Set by the first deadlock panic, the panic handler may well need the same
+/// lock and would otherwise panic again.
+#[cfg(debug_assertions)]
+static DEADLOCKED: AtomicBool = AtomicBool::new(false);
+
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    /// Index of the CPU holding the lock, `NO_HOLDER` while unlocked.
+    holder_cpu: AtomicUsize,
+    holder_location: AtomicPtr<Location<'static>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            holder_cpu: AtomicUsize::new(NO_HOLDER),
+            holder_location: AtomicPtr::new(ptr::null_mut()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// Disables interrupts and spins until the lock is free, the guard
+    /// restores the interrupt state when dropped.
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let location = Location::caller();
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if !self.try_acquire() {
+            self.spin(location);
+        }
+        self.set_holder(location);
+        SpinLockGuard { lock: self, interrupts_were_enabled }
+    }
+
+    /// Takes the lock if it's free, without spinning.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let location = Location::caller();
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if !self.try_acquire() {
+            if interrupts_were_enabled {
+                interrupts::enable();
+            }
+            return None;
+        }
+        self.set_holder(location);
+        Some(SpinLockGuard { lock: self, interrupts_were_enabled })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// CPU index and source location of the current holder, if any.
+    pub fn holder(&self) -> Option<(usize, &'static Location<'static>)> {
+        let cpu = self.holder_cpu.load(Ordering::Relaxed);
+        let location = self.holder_location.load(Ordering::Relaxed);
+        match cpu {
+            NO_HOLDER => None,
+            _ => unsafe { location.as_ref() }.map(|location| (cpu, location))
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    fn set_holder(&self, location: &'static Location<'static>) {
+        self.holder_location.store(location as *const Location<'static> as *mut Location<'static>, Ordering::Relaxed);
+        self.holder_cpu.store(percpu::current_index(), Ordering::Relaxed);
+    }
+
+    #[cold]
+    #[cfg_attr(not(debug_assertions), allow(unused_variables))]
+    fn spin(&self, location: &'static Location<'static>) {
+        #[cfg(debug_assertions)]
+        let start = crate::time::Instant::now();
+        #[cfg(debug_assertions)]
+        self.check_reentry(location);
+
+        loop {
+            while self.is_locked() {
+                spin_loop();
+                #[cfg(debug_assertions)]
+                if start.elapsed() > DEADLOCK_THRESHOLD {
+                    self.deadlock(location, "is still held");
+                }
+            }
+            if self.try_acquire() {
+                return;
+            }
+        }
+    }
+
+    /// Only the holder sets its own CPU, so seeing the current one means
+    /// the lock is taken again by the code holding it.
+    #[cfg(debug_assertions)]
+    fn check_reentry(&self, location: &'static Location<'static>) {
+        if self.holder_cpu.load(Ordering::Relaxed) == percpu::current_index() {
+            self.deadlock(location, "is already held on this CPU");
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn deadlock(&self, location: &'static Location<'static>, reason: &str) {
+        if DEADLOCKED.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        match self.holder() {
+            Some((cpu, holder)) => panic!("deadlock: lock taken at {} {}, by CPU {} at {}", location, reason, cpu, holder),
+            None => panic!("deadlock: lock taken at {} {}", location, reason),
+        }
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        SpinLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpinLock").field("holder", &self.holder()).finish()
+    }
+}
+
+/// Gives access to the data of a locked `SpinLock`.
+///
+/// Interrupts are enabled again on drop if they were when locking, so nested
+/// guards have to be dropped in reverse order.
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    interrupts_were_enabled: bool,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for SpinLockGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.holder_cpu.store(NO_HOLDER, Ordering::Relaxed);
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_lock_restores_interrupts() {
+    let lock = SpinLock::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test_case]
+fn test_lock_records_holder() {
+    let lock = SpinLock::new(());
+    assert!(lock.holder().is_none());
+    let guard = lock.lock();
+    let (cpu, location) = lock.holder().expect("no holder recorded");
+    assert_eq!(cpu, percpu::current_index());
+    assert_eq!(location.file(), file!());
+    drop(guard);
+    assert!(lock.holder().is_none());
+}
@@ -0,0 +1,289 @@
+//! System calls, entered with `syscall` or `int 0x80`.
+//!
+//! The number goes in `rax` and up to five arguments in `rdi`, `rsi`, `rdx`,
+//! `r10` and `r8`, like on Linux. The result comes back in `rax`, negative
+//! values are a `SyscallError`. Both entries preserve every register but
+//! `rax`, and `syscall` clobbers `rcx` and `r11` as the instruction does.
+
+use alloc::string::String;
+use core::{arch::global_asm, slice, time::Duration};
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        control::{Efer, EferFlags},
+        model_specific::{LStar, SFMask, Star},
+        rflags::RFlags
+    },
+    structures::paging::PageTableFlags,
+    VirtAddr
+};
+use crate::{
+    console,
+    gdt,
+    print,
+    process::{self, ExitStatus, USER_STACK_SIZE, USER_STACK_TOP},
+    thread,
+    time
+};
+
+/// Interrupt vector of the `int 0x80` entry.
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_READ: u64 = 2;
+pub const SYS_SLEEP: u64 = 3;
+pub const SYS_UPTIME: u64 = 4;
+pub const SYS_MMAP: u64 = 5;
+
+/// `mmap` protection flags, memory is always readable.
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+
+const PAGE_SIZE: u64 = 4096;
+
+type Handler = fn(&[u64; 5]) -> Result<u64, SyscallError>;
+
+/// Handlers indexed by syscall number.
+static SYSCALLS: [Handler; 6] = [
+    sys_exit,
+    sys_write,
+    sys_read,
+    sys_sleep,
+    sys_uptime,
+    sys_mmap,
+];
+
+/// Error returned to user space as its negated value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    NoSuchSyscall = 1,
+    /// A buffer isn't mapped in user space with the access needed.
+    BadAddress = 2,
+    InvalidArgument = 3,
+    OutOfMemory = 4,
+}
+
+impl SyscallError {
+    /// The error encoded in a syscall result, if it is one.
+    pub fn from_result(value: u64) -> Option<SyscallError> {
+        match (value as i64).checked_neg()? as u64 {
+            1 => Some(SyscallError::NoSuchSyscall),
+            2 => Some(SyscallError::BadAddress),
+            3 => Some(SyscallError::InvalidArgument),
+            4 => Some(SyscallError::OutOfMemory),
+            _ => None
+        }
+    }
+}
+
+// The CPU doesn't switch stacks on `syscall`, so the entry moves to the
+// kernel stack of the TSS itself, stashing the user stack pointer with
+// interrupts still masked by SFMASK. That stash is only good for one CPU.
+// Both entries swap in the kernel's GS base like the interrupt stubs do,
+// see `percpu`, `int 0x80` only when it comes from user mode.
+global_asm!(r#"
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov [rip + {user_rsp}], rsp
+    mov rsp, [rip + {tss} + 4]
+    and rsp, -16
+    push qword ptr [rip + {user_rsp}]
+    push r11
+    push rcx
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    push rax
+
+    mov r9, r8
+    mov r8, r10
+    mov rcx, rdx
+    mov rdx, rsi
+    mov rsi, rdi
+    mov rdi, rax
+    sti
+    call {dispatch}
+    cli
+
+    add rsp, 8
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rcx
+    pop r11
+    pop rsp
+    swapgs
+    sysretq
+
+.global syscall_interrupt_entry
+syscall_interrupt_entry:
+    test byte ptr [rsp + 8], 3
+    jz 2f
+    swapgs
+2:
+    push rcx
+    push r11
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    push rax
+
+    mov r9, r8
+    mov r8, r10
+    mov rcx, rdx
+    mov rdx, rsi
+    mov rsi, rdi
+    mov rdi, rax
+    sti
+    call {dispatch}
+    cli
+
+    add rsp, 8
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r11
+    pop rcx
+    test byte ptr [rsp + 8], 3
+    jz 3f
+    swapgs
+3:
+    iretq
+"#,
+    user_rsp = sym USER_RSP,
+    tss = sym gdt::TSS,
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+    fn syscall_interrupt_entry();
+}
+
+/// User stack pointer between `syscall` and switching to the kernel stack.
+static mut USER_RSP: u64 = 0;
+
+/// Enables `syscall`/`sysret`, the GDT must be loaded.
+pub fn init() {
+    Star::write(
+        gdt::user_code_selector(),
+        gdt::user_data_selector(),
+        gdt::kernel_code_selector(),
+        gdt::kernel_data_selector()
+    ).expect("GDT segments not laid out for sysret");
+    LStar::write(VirtAddr::new(syscall_entry as u64));
+    // like an interrupt gate, enters with interrupts disabled
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Address of the `int 0x80` handler, for an IDT entry callable from ring 3.
+pub(crate) fn interrupt_entry() -> VirtAddr {
+    VirtAddr::new(syscall_interrupt_entry as u64)
+}
+
+/// Called by both entries with interrupts enabled
+extern "C" fn syscall_dispatch(number: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> u64 {
+    let args = [arg1, arg2, arg3, arg4, arg5];
+    let result = match SYSCALLS.get(number as usize) {
+        Some(handler) => handler(&args),
+        None => Err(SyscallError::NoSuchSyscall)
+    };
+    if process::is_interrupted() {
+        interrupts::disable();
+        process::exit_current(ExitStatus::Interrupted);
+    }
+    match result {
+        Ok(value) => value,
+        Err(err) => (err as u64).wrapping_neg()
+    }
+}
+
+/// Whether `len` bytes at `start` are mapped in the current process for user
+/// mode, and writable as well if `writable` is set.
+fn validate(start: u64, len: u64, writable: bool) -> Result<(), SyscallError> {
+    let start = VirtAddr::try_new(start).map_err(|_| SyscallError::BadAddress)?;
+    let accessible = process::with_current(|process| {
+        process.address_space.is_accessible(start, len, writable)
+    });
+    match accessible {
+        Some(true) => Ok(()),
+        _ => Err(SyscallError::BadAddress)
+    }
+}
+
+/// `exit(code)`, doesn't return.
+fn sys_exit(args: &[u64; 5]) -> Result<u64, SyscallError> {
+    interrupts::disable();
+    process::exit_current(ExitStatus::Exited(args[0] as u32))
+}
+
+/// `write(buf, len)` prints `len` bytes of UTF-8 to the console, returning
+/// `len`.
+fn sys_write(args: &[u64; 5]) -> Result<u64, SyscallError> {
+    let (start, len) = (args[0], args[1]);
+    validate(start, len, false)?;
+    // the process's address space is active, it can be read in place
+    let bytes = unsafe { slice::from_raw_parts(start as *const u8, len as usize) };
+    print!("{}", String::from_utf8_lossy(bytes));
+    Ok(len)
+}
+
+/// `read(buf, len)` blocks until there is keyboard input, returning the
+/// number of bytes read.
+fn sys_read(args: &[u64; 5]) -> Result<u64, SyscallError> {
+    let (start, len) = (args[0], args[1]);
+    validate(start, len, true)?;
+    let buf = unsafe { slice::from_raw_parts_mut(start as *mut u8, len as usize) };
+    Ok(console::read(buf) as u64)
+}
+
+/// `sleep(milliseconds)`
+fn sys_sleep(args: &[u64; 5]) -> Result<u64, SyscallError> {
+    thread::sleep(Duration::from_millis(args[0]));
+    Ok(0)
+}
+
+/// `uptime()` in nanoseconds.
+fn sys_uptime(_args: &[u64; 5]) -> Result<u64, SyscallError> {
+    Ok(time::get_system_uptime().as_nanos() as u64)
+}
+
+/// `mmap(len, prot)` maps zeroed memory, returning its address.
+fn sys_mmap(args: &[u64; 5]) -> Result<u64, SyscallError> {
+    let (len, prot) = (args[0], args[1]);
+    if len == 0 || prot & !(PROT_READ | PROT_WRITE) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let len = len.checked_add(PAGE_SIZE - 1).ok_or(SyscallError::InvalidArgument)? & !(PAGE_SIZE - 1);
+    let flags = match prot & PROT_WRITE {
+        0 => PageTableFlags::empty(),
+        _ => PageTableFlags::WRITABLE
+    };
+
+    process::with_current(|process| {
+        let start = process.mmap_next;
+        let end = start.checked_add(len).filter(|&end| end <= USER_STACK_TOP - USER_STACK_SIZE)
+            .ok_or(SyscallError::OutOfMemory)?;
+        // skipped even on failure, pages that did get mapped stay until exit
+        process.mmap_next = end;
+        process.address_space.map(VirtAddr::new(start), len, flags)
+            .map_err(|_| SyscallError::OutOfMemory)?;
+        Ok(start)
+    }).unwrap_or(Err(SyscallError::InvalidArgument))
+}
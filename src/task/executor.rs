@@ -1,58 +1,128 @@
-use super::{Task, TaskId, spawner::{SPAWNER, Spawner}};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use crossbeam_queue::ArrayQueue;
-use core::task::{Context, Poll, Waker};
+use super::{Priority, Task, TaskId, TaskInfo, TaskSnapshot, sleep, spawner::SPAWNER, watchdog};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{self, AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Waker}
+};
+use spin::{Mutex, RwLock};
+use crate::{smp::{self, percpu, MAX_CPUS}, spinlock::SpinLock, time::Instant};
 
+/// A lower priority with ready tasks gets to run a task after being passed
+/// over this many times, so it can't starve.
+const STARVATION_LIMIT: usize = 32;
+
+/// Info of the tasks owned by the executors, for introspection.
+static TASK_REGISTRY: SpinLock<BTreeMap<TaskId, Arc<TaskInfo>>> = SpinLock::new(BTreeMap::new());
+
+/// Lists the tasks currently owned by the executors.
+pub fn task_snapshot() -> Vec<TaskSnapshot> {
+    TASK_REGISTRY.lock()
+        .iter()
+        .map(|(task_id, info)| TaskSnapshot::new(*task_id, info))
+        .collect()
+}
+
+/// Run queues of the CPUs with an executor, indexed by CPU.
+static CPU_QUEUES: [OnceCell<CpuQueues>; MAX_CPUS] = [const { OnceCell::uninit() }; MAX_CPUS];
+
+/// Number of tasks of each priority on all CPUs. A CPU grows its queues to
+/// this many before taking a task in, which keeps room for every task that
+/// could get queued on it.
+static TASK_COUNTS: [AtomicUsize; Priority::COUNT] = [const { AtomicUsize::new(0) }; Priority::COUNT];
+
+/// Runs tasks on the current CPU, there is one per CPU.
+///
+/// Tasks are queued on the CPU that last polled them when woken. A CPU
+/// without ready tasks steals them from the others before halting, and
+/// halted CPUs are woken with an IPI when tasks are queued or spawned.
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    shared_task_queue: Arc<ArrayQueue<Task>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    cpu: usize,
+    run_queue: RunQueue,
+    shared_task_queue: &'static SegQueue<Task>,
 }
 
 impl Executor {
     pub fn new() -> Self {
-        let shared_task_queue = SPAWNER.get_or_init(|| { Spawner::new() }).clone_shared_task_queue();
+        let cpu = percpu::current().index();
         Executor {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            shared_task_queue,
-            waker_cache: BTreeMap::new(),
+            cpu,
+            run_queue: RunQueue::new(CPU_QUEUES[cpu].get_or_init(CpuQueues::new)),
+            shared_task_queue: &SPAWNER.shared_task_queue,
         }
     }
 
     fn run_ready_tasks(&mut self) {
-        // destructure `self` to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            shared_task_queue: _,
-            waker_cache,
-        } = self;
-
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+        while let Some(task) = self.run_queue.pop().or_else(|| self.steal()) {
+            self.run_task(&task);
+        }
+    }
+
+    fn run_task(&self, task: &Arc<SharedTask>) {
+        // only contended when a thief took the task off the queue while it was
+        // still being polled
+        let mut future = task.future.lock();
+        let poll = match future.as_mut() {
+            Some(future) => {
+                // wakers from now on queue it here
+                task.cpu.store(self.cpu, Ordering::Relaxed);
+                task.info.ready.store(false, Ordering::Release);
+                let waker = Waker::from(task.clone());
+                let mut context = Context::from_waker(&waker);
+                let poll_start = Instant::now();
+                watchdog::poll_started(task.id, &task.info);
+                let poll = future.as_mut().poll(&mut context);
+                watchdog::poll_finished();
+                task.info.record_poll(poll_start.elapsed());
+                poll
+            }
+            None => return, // completed while waiting for the lock
+        };
+        if poll.is_ready() {
+            // task done -> drop its future, leaving it marked ready so wakers
+            // that outlive it don't queue it
+            *future = None;
+            task.info.ready.store(true, Ordering::Release);
+            TASK_COUNTS[task.priority.index()].fetch_sub(1, Ordering::Relaxed);
+            TASK_REGISTRY.lock().remove(&task.id);
+        }
+    }
+
+    /// Takes a ready task from another CPU, the highest priority first.
+    fn steal(&self) -> Option<Arc<SharedTask>> {
+        for priority in Priority::ALL {
+            // start after this CPU, so thieves spread out over their victims
+            let victims = (1..MAX_CPUS).map(|offset| (self.cpu + offset) % MAX_CPUS);
+            for victim in victims.filter_map(|victim| CPU_QUEUES[victim].get()) {
+                if victim.queue(priority).read().is_empty() {
+                    continue;
+                }
+                // make room before the task can get woken here
+                self.run_queue.queues.reserve(priority, TASK_COUNTS[priority.index()].load(Ordering::Relaxed));
+                if let Some(task) = victim.queue(priority).read().pop() {
+                    return Some(task);
                 }
-                Poll::Pending => {}
             }
         }
+        None
+    }
+
+    /// Whether any CPU has a ready task this one could steal.
+    fn can_steal(&self) -> bool {
+        CPU_QUEUES.iter()
+            .enumerate()
+            .filter(|&(cpu, _)| cpu != self.cpu)
+            .filter_map(|(_, queues)| queues.get())
+            .any(|queues| !queues.is_empty())
     }
 
     pub fn run(&mut self) -> ! {
         loop {
             self.spawn_new_tasks();
+            sleep::wake_expired_sleepers();
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
@@ -60,45 +130,247 @@ impl Executor {
 
     fn spawn_new_tasks(&mut self) {
         while let Some(task) = self.shared_task_queue.pop() {
-            let task_id = task.id;
-            if self.tasks.insert(task.id, task).is_some() {
-                panic!("task with same ID already in tasks");
+            let Task { id, priority, info, future } = task;
+            info.ready.store(true, Ordering::Relaxed);
+            if TASK_REGISTRY.lock().insert(id, info.clone()).is_some() {
+                panic!("task with same ID already spawned");
             }
-            self.task_queue.push(task_id).expect("queue full");
+            let task_count = TASK_COUNTS[priority.index()].fetch_add(1, Ordering::Relaxed) + 1;
+            let queues = self.run_queue.queues;
+            queues.reserve(priority, task_count);
+            queues.push(priority, Arc::new(SharedTask {
+                id,
+                priority,
+                info,
+                future: Mutex::new(Some(future)),
+                cpu: AtomicUsize::new(self.cpu),
+            }));
         }
     }
 
+    /// Halts until the next interrupt when there is nothing to run.
+    ///
+    /// Instead of waking up on every periodic tick, the timer of the
+    /// bootstrap processor is armed to fire once at the earliest deadline.
+    /// The other CPUs have no timer and rely on wake up IPIs: their sleeps
+    /// time out in the timer interrupt handler, and `Sleep` wakes the
+    /// bootstrap processor to arm its timer again for an earlier deadline.
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        use crate::{thread, time::{self, Instant}};
 
+        let idle = &self.run_queue.queues.idle;
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        // pairs with the fence in `notify`, either the waker sees the flag or
+        // this sees the queued task
+        idle.store(true, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        if !self.run_queue.is_empty() || !self.shared_task_queue.is_empty() || self.can_steal() {
+            idle.store(false, Ordering::Relaxed);
+            interrupts::enable();
+            return;
+        }
+
+        if !percpu::current().is_bootstrap() {
             enable_and_hlt();
-        } else {
+            idle.store(false, Ordering::Relaxed);
+            return;
+        }
+
+        // halting would hold up the other threads until the next tick
+        if thread::has_ready_threads() {
+            idle.store(false, Ordering::Relaxed);
             interrupts::enable();
+            thread::yield_now();
+            return;
+        }
+
+        let deadline = [sleep::next_deadline(), thread::next_wakeup()].into_iter().flatten().min();
+        if deadline.map_or(false, |deadline| deadline <= Instant::now()) {
+            idle.store(false, Ordering::Relaxed);
+            interrupts::enable();
+            return;
+        }
+
+        let tickless = time::program_one_shot(deadline);
+        enable_and_hlt();
+        idle.store(false, Ordering::Relaxed);
+        if tickless {
+            interrupts::without_interrupts(time::resume_periodic_tick);
         }
     }
 }
 
-struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+/// Called after spawning a task
+///
+/// Wakes a halted CPU to take it in, if there is one.
+pub(crate) fn wake_idle_cpu() {
+    atomic::fence(Ordering::SeqCst);
+    let current = percpu::current().index();
+    let idle = CPU_QUEUES.iter()
+        .enumerate()
+        .filter(|&(cpu, _)| cpu != current)
+        .filter_map(|(cpu, queues)| Some((cpu, queues.get()?)))
+        // claim the flag, so other wakers pick another CPU
+        .find(|(_, queues)| queues.idle.compare_exchange(true, false, Ordering::AcqRel, Ordering::Relaxed).is_ok());
+    if let Some((cpu, _)) = idle {
+        smp::wake_up(cpu);
+    }
 }
 
-impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+/// Makes sure a task just queued on `cpu` gets run.
+///
+/// A halted CPU is woken up, a busy one gets help from an idle CPU that
+/// steals the task instead. The current CPU needs no IPI, if it's halted
+/// this runs in an interrupt handler that ends the `hlt`.
+fn notify(cpu: usize) {
+    atomic::fence(Ordering::SeqCst);
+    let queues = cpu_queues(cpu);
+    if !queues.idle.load(Ordering::Relaxed) {
+        wake_idle_cpu();
+    } else if cpu != percpu::current().index() && queues.idle.swap(false, Ordering::AcqRel) {
+        smp::wake_up(cpu);
     }
+}
+
+fn cpu_queues(cpu: usize) -> &'static CpuQueues {
+    CPU_QUEUES[cpu].get().expect("no executor on the CPU of a task")
+}
 
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+/// Queue of ready tasks of one priority.
+///
+/// A task is only queued while it isn't already, and only on the CPU that
+/// last polled it, which grew the queue to the number of tasks before taking
+/// it in. The queue never fills up that way. The owning CPU grows it with
+/// interrupts disabled, wakers only ever take the read lock to push.
+type TaskQueue = RwLock<ArrayQueue<Arc<SharedTask>>>;
+
+const INITIAL_QUEUE_CAPACITY: usize = 100;
+
+/// Ready tasks of one CPU, with a queue per priority.
+struct CpuQueues {
+    queues: [TaskQueue; Priority::COUNT],
+    /// Set while the CPU halts or is about to, wakers clear it when they send
+    /// a wake up IPI.
+    idle: AtomicBool,
+}
+
+impl CpuQueues {
+    fn new() -> Self {
+        CpuQueues {
+            queues: Priority::ALL.map(|_| RwLock::new(ArrayQueue::new(INITIAL_QUEUE_CAPACITY))),
+            idle: AtomicBool::new(false),
+        }
+    }
+
+    fn queue(&self, priority: Priority) -> &TaskQueue {
+        &self.queues[priority.index()]
+    }
+
+    fn push(&self, priority: Priority, task: Arc<SharedTask>) {
+        // can only fail if a task got queued twice or the executor didn't reserve
+        if self.queue(priority).read().push(task).is_err() {
+            panic!("run queue smaller than the number of tasks");
+        }
+    }
+
+    /// Makes room in the queue of `priority` for at least `len` tasks.
+    fn reserve(&self, priority: Priority, len: usize) {
+        use x86_64::instructions::interrupts;
+
+        let queue = self.queue(priority);
+        if queue.read().capacity() >= len {
+            return;
+        }
+
+        // a waker running in an interrupt handler would spin on the write lock
+        interrupts::without_interrupts(|| {
+            let mut queue = queue.write();
+            let grown = ArrayQueue::new(len.max(queue.capacity() * 2));
+            while let Some(task) = queue.pop() {
+                if grown.push(task).is_err() {
+                    panic!("grown run queue is smaller than the old one");
+                }
+            }
+            *queue = grown;
+        });
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.read().is_empty())
+    }
+}
+
+/// Ready tasks of the current CPU, taken by priority.
+struct RunQueue {
+    queues: &'static CpuQueues,
+    /// How many times in a row each priority was passed over while it had
+    /// ready tasks.
+    passed_over: [usize; Priority::COUNT],
+}
+
+impl RunQueue {
+    fn new(queues: &'static CpuQueues) -> Self {
+        RunQueue {
+            queues,
+            passed_over: [0; Priority::COUNT],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    fn is_queue_empty(&self, index: usize) -> bool {
+        self.queues.queues[index].read().is_empty()
+    }
+
+    /// Takes the next task from the highest priority that has one, unless a
+    /// lower priority has been passed over too many times.
+    fn pop(&mut self) -> Option<Arc<SharedTask>> {
+        let starving = Priority::ALL.iter().rev()
+            .map(|priority| priority.index())
+            .find(|&index| self.passed_over[index] >= STARVATION_LIMIT && !self.is_queue_empty(index));
+        if let Some(index) = starving {
+            self.passed_over[index] = 0;
+            return self.queues.queues[index].read().pop();
+        }
+
+        let index = (0..Priority::COUNT).find(|&index| !self.is_queue_empty(index))?;
+        self.passed_over[index] = 0;
+        for lower in index + 1..Priority::COUNT {
+            if !self.is_queue_empty(lower) {
+                self.passed_over[lower] += 1;
+            }
+        }
+        self.queues.queues[index].read().pop()
     }
 }
 
-impl Wake for TaskWaker {
+/// A spawned task, shared by the run queues and wakers of every CPU.
+struct SharedTask {
+    id: TaskId,
+    priority: Priority,
+    info: Arc<TaskInfo>,
+    /// `None` once the task has completed.
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    /// Index of the CPU that last polled the task, it gets queued there.
+    cpu: AtomicUsize,
+}
+
+impl SharedTask {
+    /// Queues the task, unless it's already queued or has completed.
+    fn wake_task(self: &Arc<Self>) {
+        if self.info.ready.compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            let cpu = self.cpu.load(Ordering::Relaxed);
+            cpu_queues(cpu).push(self.priority, self.clone());
+            notify(cpu);
+        }
+    }
+}
+
+// waking only clones the `Arc`, so it works in interrupt handlers
+impl Wake for SharedTask {
     fn wake(self: Arc<Self>) {
         self.wake_task();
     }
@@ -106,4 +378,36 @@ impl Wake for TaskWaker {
     fn wake_by_ref(self: &Arc<Self>) {
         self.wake_task();
     }
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_background_task_runs_despite_interactive_tasks() {
+    let queues: &'static CpuQueues = Box::leak(Box::new(CpuQueues::new()));
+    let mut run_queue = RunQueue::new(queues);
+    let queue_task = |priority| {
+        let Task { id, priority, info, future } = Task::with_priority(async {}, priority);
+        queues.push(priority, Arc::new(SharedTask {
+            id,
+            priority,
+            info,
+            future: Mutex::new(Some(future)),
+            cpu: AtomicUsize::new(0),
+        }));
+    };
+    queue_task(Priority::Background);
+    queue_task(Priority::Interactive);
+    queue_task(Priority::Interactive);
+
+    // the interactive tasks wake themselves every time they run
+    let mut interactive_runs = 0;
+    loop {
+        let task = run_queue.pop().expect("run queue emptied");
+        if task.priority == Priority::Background {
+            break;
+        }
+        interactive_runs += 1;
+        queues.push(task.priority, task);
+    }
+    assert_eq!(interactive_runs, STARVATION_LIMIT);
+    assert_eq!(run_queue.pop().map(|task| task.priority), Some(Priority::Interactive));
+}
@@ -0,0 +1,209 @@
+//! Input events, published by the keyboard and mouse tasks.
+//!
+//! Any number of subscribers observe every event, while only the holder of
+//! the focus on top of the focus stack gets them to act on. The shell holds
+//! the bottom one, so an editor or game grabbing the focus takes over the
+//! input until it drops its `Focus`.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::spinlock::SpinLock;
+use super::sync::mpsc::{self, Receiver, Recv, Sender, TrySendError};
+
+pub use pc_keyboard::KeyCode;
+
+/// Events buffered per subscriber or focus, later ones are dropped while
+/// it's full.
+const CAPACITY: usize = 64;
+
+static SUBSCRIBERS: SpinLock<Vec<Sender<InputEvent>>> = SpinLock::new(Vec::new());
+/// Focus holders, the last one gets the events.
+static FOCUS: SpinLock<Vec<(u64, Sender<InputEvent>)>> = SpinLock::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    Key(KeyEvent),
+    /// Character typed with the current keymap, after dead keys.
+    Text(char),
+    Mouse(MouseEvent),
+}
+
+/// A key going down, again while held, or up. Keys without a `KeyCode`,
+/// see `keymap::ExtraKeys`, only produce `InputEvent::Text`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub pressed: bool,
+    /// State after this event.
+    pub modifiers: Modifiers,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    /// Nothing held, with num lock on like the BIOS leaves it.
+    pub const fn new() -> Self {
+        Modifiers {
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            alt: false,
+            alt_gr: false,
+            caps_lock: false,
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+
+    pub fn is_shifted(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn is_ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    /// Tracks the modifier keys, lock keys toggle when pressed.
+    pub(crate) fn update(&mut self, code: KeyCode, pressed: bool) {
+        match code {
+            KeyCode::ShiftLeft => self.left_shift = pressed,
+            KeyCode::ShiftRight => self.right_shift = pressed,
+            KeyCode::ControlLeft => self.left_ctrl = pressed,
+            KeyCode::ControlRight => self.right_ctrl = pressed,
+            KeyCode::AltLeft => self.alt = pressed,
+            KeyCode::AltRight => self.alt_gr = pressed,
+            KeyCode::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock if pressed => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock if pressed => self.scroll_lock = !self.scroll_lock,
+            _ => {}
+        }
+    }
+}
+
+impl Default for Modifiers {
+    fn default() -> Self {
+        Modifiers::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    /// Fourth button of a five button mouse.
+    Back,
+    /// Fifth button of a five button mouse.
+    Forward,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseEvent {
+    /// Relative movement, `dy` is positive upwards.
+    Move { dx: i16, dy: i16 },
+    Button { button: MouseButton, pressed: bool },
+    /// Wheel turned by `lines`, positive towards the user.
+    Scroll { lines: i8 },
+}
+
+/// Receives every event, whoever has the focus.
+pub fn subscribe() -> Receiver<InputEvent> {
+    let (sender, receiver) = mpsc::channel(CAPACITY);
+    SUBSCRIBERS.lock().push(sender);
+    receiver
+}
+
+/// Takes the focus from its current holder until the returned `Focus` is
+/// dropped.
+pub fn grab_focus() -> Focus {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = mpsc::channel(CAPACITY);
+    FOCUS.lock().push((id, sender));
+    Focus { id, receiver }
+}
+
+/// Called by the keyboard and mouse tasks
+///
+/// Hands `event` to the subscribers and the focus holder.
+pub(crate) fn dispatch(event: InputEvent) {
+    SUBSCRIBERS.lock().retain(|subscriber| !matches!(subscriber.try_send(event), Err(TrySendError::Closed(_))));
+    if let Some((_, holder)) = FOCUS.lock().last() {
+        let _ = holder.try_send(event);
+    }
+}
+
+/// Handle of a focus holder, see `grab_focus`.
+pub struct Focus {
+    id: u64,
+    receiver: Receiver<InputEvent>,
+}
+
+impl Focus {
+    /// Receives the next event sent while this one had the focus.
+    pub fn recv(&mut self) -> Recv<'_, InputEvent> {
+        self.receiver.recv()
+    }
+
+    pub fn try_recv(&mut self) -> Option<InputEvent> {
+        self.receiver.try_recv()
+    }
+
+    pub fn has_focus(&self) -> bool {
+        FOCUS.lock().last().map_or(false, |(id, _)| *id == self.id)
+    }
+}
+
+impl Drop for Focus {
+    fn drop(&mut self) {
+        FOCUS.lock().retain(|(id, _)| *id != self.id);
+    }
+}
+
+#[test_case]
+fn test_focus_goes_back_when_dropped() {
+    let mut shell = grab_focus();
+    let mut editor = grab_focus();
+    assert!(editor.has_focus() && !shell.has_focus());
+    dispatch(InputEvent::Text('a'));
+    assert_eq!(editor.try_recv(), Some(InputEvent::Text('a')));
+    assert_eq!(shell.try_recv(), None);
+
+    drop(editor);
+    assert!(shell.has_focus());
+    dispatch(InputEvent::Text('b'));
+    assert_eq!(shell.try_recv(), Some(InputEvent::Text('b')));
+}
+
+#[test_case]
+fn test_subscribers_get_every_event() {
+    let mut subscriber = subscribe();
+    let _focus = grab_focus();
+    let event = InputEvent::Mouse(MouseEvent::Move { dx: 1, dy: -1 });
+    dispatch(event);
+    assert_eq!(subscriber.try_recv(), Some(event));
+}
+
+#[test_case]
+fn test_modifiers_track_keys() {
+    let mut modifiers = Modifiers::new();
+    modifiers.update(KeyCode::ShiftLeft, true);
+    modifiers.update(KeyCode::CapsLock, true);
+    modifiers.update(KeyCode::CapsLock, false);
+    assert!(modifiers.is_shifted() && modifiers.caps_lock);
+    modifiers.update(KeyCode::ShiftLeft, false);
+    assert!(!modifiers.is_shifted());
+}
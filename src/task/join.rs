@@ -0,0 +1,114 @@
+use super::{Priority, Task};
+use alloc::{borrow::Cow, sync::Arc};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll}
+};
+use futures_util::{
+    future::{abortable, AbortHandle},
+    task::AtomicWaker
+};
+use crate::spinlock::SpinLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was cancelled with `JoinHandle::abort` before completing.
+    Aborted,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Aborted => write!(f, "task was aborted")
+        }
+    }
+}
+
+struct JoinState<T> {
+    result: SpinLock<Option<Result<T, JoinError>>>,
+    finished: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl<T> JoinState<T> {
+    fn complete(&self, result: Result<T, JoinError>) {
+        *self.result.lock() = Some(result);
+        self.finished.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+/// Owned permission to await the output of a spawned task.
+///
+/// Dropping the handle detaches the task, it keeps running to completion.
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+    abort_handle: AbortHandle,
+}
+
+impl<T> JoinHandle<T> {
+    /// Cancels the task, it's dropped the next time the executor polls it and
+    /// awaiting the handle resolves to `JoinError::Aborted`.
+    pub fn abort(&self) {
+        self.abort_handle.abort();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // fast path
+        if let Some(result) = self.state.result.lock().take() {
+            return Poll::Ready(result);
+        }
+
+        self.state.waker.register(cx.waker());
+        match self.state.result.lock().take() {
+            Some(result) => {
+                self.state.waker.take();
+                Poll::Ready(result)
+            },
+            None => Poll::Pending
+        }
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinHandle").field("finished", &self.is_finished()).finish()
+    }
+}
+
+/// Wraps `future` in a task that reports its output to the returned handle.
+pub(crate) fn joinable<F>(
+    name: Cow<'static, str>,
+    priority: Priority,
+    future: F
+) -> (Task, JoinHandle<F::Output>)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let state = Arc::new(JoinState {
+        result: SpinLock::new(None),
+        finished: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+    let (future, abort_handle) = abortable(future);
+
+    let task_state = state.clone();
+    let task = Task::named(name, priority, async move {
+        let result = future.await.map_err(|_| JoinError::Aborted);
+        task_state.complete(result);
+    });
+
+    (task, JoinHandle { state, abort_handle })
+}
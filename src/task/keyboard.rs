@@ -1,70 +1,167 @@
 use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
-use crate::command;
-use core::{pin::Pin, task::{Poll, Context}};
-use futures_util::{stream::Stream, task::AtomicWaker, stream::StreamExt};
-use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+use crate::{keymap::{self, CurrentLayout, DeadKeys, ExtraKeys}, ps2::{self, Device}};
+use super::{input::{self, InputEvent, KeyEvent, Modifiers}, sync::mpsc::{self, Receiver, Sender}};
+use pc_keyboard::{DecodedKey, HandleControl, KeyState, Keyboard, ScancodeSet1};
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
+static SCANCODE_SENDER: OnceCell<Sender<u8>> = OnceCell::uninit();
 
 /// Called by the keyboard interrupt handler
 ///
 /// Must not block or allocate.
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Ok(_)  = queue.push(scancode) {
-            WAKER.wake();
-        }
+    if let Ok(sender) = SCANCODE_SENDER.try_get() {
+        let _ = sender.try_send(scancode);
     }
 }
 
-pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1,
-        HandleControl::Ignore);
+/// Creates the channel the keyboard interrupt handler sends scancodes to.
+pub fn scancode_receiver() -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel(100);
+    SCANCODE_SENDER.try_init_once(|| sender)
+        .expect("scancode_receiver should only be called once");
+    receiver
+}
+
+/// Decodes scancodes into key and text events for `input`.
+pub async fn process_scancodes() {
+    let mut scancodes = scancode_receiver();
+    // Ctrl+letter comes as the control character, Ctrl+C as U+0003
+    let mut keyboard = Keyboard::new(CurrentLayout, ScancodeSet1,
+        HandleControl::MapLettersToUnicode);
+    let mut extra_keys = ExtraKeys::new();
+    let mut dead_keys = DeadKeys::new();
+    let mut modifiers = Modifiers::new();
+    let mut leds = Leds::new(lock_leds(&modifiers));
+
+    while let Some(scancode) = scancodes.recv().await {
+        if scancode == ps2::ACK || scancode == ps2::RESEND {
+            let reply = if scancode == ps2::ACK { leds.acknowledged() } else { leds.resend() };
+            if let Some(byte) = reply {
+                ps2::send(Device::Keyboard, byte);
+            }
+            continue;
+        }
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                command::COMMAND_PROCESSOR.lock().process_key(key);
+        let text = match extra_keys.add_byte(scancode) {
+            Some(character) => character,
+            None => match keyboard.add_byte(scancode) {
+                Ok(Some(key_event)) => {
+                    let pressed = key_event.state == KeyState::Down;
+                    modifiers.update(key_event.code, pressed);
+                    if let Some(byte) = leds.set(lock_leds(&modifiers)) {
+                        ps2::send(Device::Keyboard, byte);
+                    }
+                    input::dispatch(InputEvent::Key(KeyEvent { code: key_event.code, pressed, modifiers }));
+                    match keyboard.process_keyevent(key_event) {
+                        Some(DecodedKey::Unicode(character)) => Some(character),
+                        _ => None
+                    }
+                },
+                _ => None
+            }
+        };
+        if let Some(character) = text {
+            for character in dead_keys.compose(keymap::current(), character) {
+                input::dispatch(InputEvent::Text(character));
             }
         }
     }
 }
 
-pub struct ScancodeStream {
-    _private: (),
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedCommand {
+    Idle,
+    /// `SET_LEDS` was sent, the LED byte follows its ACK.
+    Command,
+    /// The LED byte was sent.
+    Data,
 }
 
-impl ScancodeStream {
-    pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
-    }
+/// Sends the lock key state to the keyboard, one `SET_LEDS` at a time.
+///
+/// Each method returns the byte to send to the keyboard, if any. A change
+/// made while a command is in flight is sent once it's acknowledged.
+struct Leds {
+    /// LEDs on the keyboard, or being sent to it.
+    sent: u8,
+    wanted: u8,
+    command: LedCommand,
 }
 
-impl Stream for ScancodeStream {
-    type Item = u8;
+impl Leds {
+    fn new(leds: u8) -> Self {
+        Leds {
+            sent: leds,
+            wanted: leds,
+            command: LedCommand::Idle,
+        }
+    }
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("scancode queue not initialized");
+    fn set(&mut self, leds: u8) -> Option<u8> {
+        self.wanted = leds;
+        self.start()
+    }
 
-        // fast path
-        if let Some(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
+    fn start(&mut self) -> Option<u8> {
+        if self.command != LedCommand::Idle || self.wanted == self.sent {
+            return None;
         }
+        self.command = LedCommand::Command;
+        Some(ps2::SET_LEDS)
+    }
 
-        WAKER.register(&cx.waker());
-        match queue.pop() {
-            Some(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
+    fn acknowledged(&mut self) -> Option<u8> {
+        match self.command {
+            LedCommand::Idle => None,
+            LedCommand::Command => {
+                self.command = LedCommand::Data;
+                self.sent = self.wanted;
+                Some(self.sent)
+            },
+            LedCommand::Data => {
+                self.command = LedCommand::Idle;
+                self.start()
             }
-            None => Poll::Pending,
         }
     }
+
+    /// Sends the last byte again, the keyboard didn't get it.
+    fn resend(&mut self) -> Option<u8> {
+        match self.command {
+            LedCommand::Idle => None,
+            LedCommand::Command => Some(ps2::SET_LEDS),
+            LedCommand::Data => Some(self.sent)
+        }
+    }
+}
+
+fn lock_leds(modifiers: &Modifiers) -> u8 {
+    let mut leds = 0;
+    if modifiers.caps_lock {
+        leds |= ps2::LED_CAPS_LOCK;
+    }
+    if modifiers.num_lock {
+        leds |= ps2::LED_NUM_LOCK;
+    }
+    if modifiers.scroll_lock {
+        leds |= ps2::LED_SCROLL_LOCK;
+    }
+    leds
+}
+
+#[test_case]
+fn test_leds_wait_for_ack() {
+    let mut leds = Leds::new(0);
+    assert_eq!(leds.set(ps2::LED_CAPS_LOCK), Some(ps2::SET_LEDS));
+    // a lock key pressed before the ACK doesn't start another command
+    assert_eq!(leds.set(ps2::LED_CAPS_LOCK | ps2::LED_NUM_LOCK), None);
+    assert_eq!(leds.resend(), Some(ps2::SET_LEDS));
+    assert_eq!(leds.acknowledged(), Some(ps2::LED_CAPS_LOCK | ps2::LED_NUM_LOCK));
+    assert_eq!(leds.set(ps2::LED_NUM_LOCK), None);
+    assert_eq!(leds.resend(), Some(ps2::LED_CAPS_LOCK | ps2::LED_NUM_LOCK));
+    // the change made meanwhile follows
+    assert_eq!(leds.acknowledged(), Some(ps2::SET_LEDS));
+    assert_eq!(leds.acknowledged(), Some(ps2::LED_NUM_LOCK));
+    assert_eq!(leds.acknowledged(), None);
+    assert_eq!(leds.acknowledged(), None);
 }
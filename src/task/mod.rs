@@ -1,19 +1,26 @@
-use alloc::boxed::Box;
+use alloc::{borrow::Cow, boxed::Box, sync::Arc};
 use core::{
     future::Future,
     pin::Pin,
-    sync::atomic::{AtomicU64, Ordering},
-    task::{Context, Poll}
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration
 };
+use crate::time::Instant;
 
 pub mod simple_executor;
 pub mod executor;
+pub mod input;
+pub mod join;
 pub mod keyboard;
 pub mod sleep;
 pub mod spawner;
+pub mod watchdog;
 
 #[cfg(feature="mouse")]
 pub mod mouse;
+#[cfg(feature="mouse")]
+pub mod pointer;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
@@ -25,19 +32,91 @@ impl TaskId {
     }
 }
 
+/// Scheduling class of a task, the executor runs higher priorities first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Deferred work of interrupt handlers, like decoding input.
+    BottomHalf,
+    /// Tasks the user is waiting on, like the shell.
+    Interactive,
+    /// Long running jobs.
+    Background,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+    pub const ALL: [Priority; Priority::COUNT] = [Priority::BottomHalf, Priority::Interactive, Priority::Background];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Interactive
+    }
+}
+
+/// Bookkeeping about a task, shared between the executor and the wakers.
+pub(crate) struct TaskInfo {
+    name: Cow<'static, str>,
+    priority: Priority,
+    spawn_time: Instant,
+    poll_count: AtomicU64,
+    poll_nanos: AtomicU64,
+    /// Set while the task is in the run queue, and for good once it has
+    /// completed. Wakers only queue the task when they are the one setting it.
+    ready: AtomicBool,
+}
+
+impl TaskInfo {
+    fn record_poll(&self, duration: Duration) {
+        self.poll_count.fetch_add(1, Ordering::Relaxed);
+        self.poll_nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
 pub struct Task {
     id: TaskId,
+    priority: Priority,
+    info: Arc<TaskInfo>,
     future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task::with_priority(future, Priority::default())
+    }
+
+    pub fn with_priority(future: impl Future<Output = ()> + Send + 'static, priority: Priority) -> Task {
+        Task::named("unnamed", priority, future)
+    }
+
+    pub fn named(
+        name: impl Into<Cow<'static, str>>,
+        priority: Priority,
+        future: impl Future<Output = ()> + Send + 'static
+    ) -> Task {
         Task {
             id: TaskId::new(),
+            priority,
+            info: Arc::new(TaskInfo {
+                name: name.into(),
+                priority,
+                spawn_time: Instant::now(),
+                poll_count: AtomicU64::new(0),
+                poll_nanos: AtomicU64::new(0),
+                ready: AtomicBool::new(false),
+            }),
             future: Box::pin(future),
         }
     }
 
+    pub fn name(&self) -> &str {
+        &self.info.name
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
@@ -45,6 +124,43 @@ impl Task {
 
 impl core::fmt::Debug for Task {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("Task").field("id", &self.id).finish()
+        f.debug_struct("Task")
+            .field("id", &self.id)
+            .field("name", &self.info.name)
+            .field("priority", &self.priority)
+            .finish()
+    }
+}
+
+/// Whether a task is waiting in the run queue or for a wake up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,
+    Pending,
+}
+
+/// Point in time view of a task, see `executor::task_snapshot`.
+#[derive(Debug, Clone)]
+pub struct TaskSnapshot {
+    pub id: u64,
+    pub name: Cow<'static, str>,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub spawn_time: Instant,
+    pub poll_count: u64,
+    pub poll_time: Duration,
+}
+
+impl TaskSnapshot {
+    fn new(id: TaskId, info: &TaskInfo) -> TaskSnapshot {
+        TaskSnapshot {
+            id: id.0,
+            name: info.name.clone(),
+            priority: info.priority,
+            state: if info.ready.load(Ordering::Relaxed) { TaskState::Ready } else { TaskState::Pending },
+            spawn_time: info.spawn_time,
+            poll_count: info.poll_count.load(Ordering::Relaxed),
+            poll_time: Duration::from_nanos(info.poll_nanos.load(Ordering::Relaxed)),
+        }
     }
 }
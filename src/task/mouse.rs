@@ -1,84 +1,176 @@
-use conquer_once::spin::{Lazy, OnceCell};
-use core::{
-    pin::Pin,
-    task::{Context, Poll},
-};
-use crossbeam_queue::ArrayQueue;
-use futures_util::{
-    stream::{Stream, StreamExt},
-    task::AtomicWaker,
+//! PS/2 mouse, with the IntelliMouse extensions for the scroll wheel and the
+//! fourth and fifth buttons.
+//!
+//! https://wiki.osdev.org/PS/2_Mouse
+
+use conquer_once::spin::OnceCell;
+use crate::ps2::{self, Controller, Device, DeviceType, Ps2Error};
+use super::{
+    input::{self, InputEvent, MouseButton, MouseEvent},
+    sync::mpsc::{self, Receiver, Sender}
 };
-use ps2_mouse::{Mouse as PS2Mouse, MouseState};
-use spinning_top::Spinlock;
 
-static WAKER: AtomicWaker = AtomicWaker::new();
-pub static MOUSE: Lazy<Spinlock<PS2Mouse>> = Lazy::new(|| Spinlock::new(PS2Mouse::new()));
-static MOUSE_PACKET_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+const SET_SAMPLE_RATE: u8 = 0xF3;
+const SET_DEFAULTS: u8 = 0xF6;
+
+/// Sample rates that unlock the scroll wheel, turning the mouse into
+/// device type 3.
+const WHEEL_SEQUENCE: [u8; 3] = [200, 100, 80];
+/// Sample rates that unlock the extra buttons of a wheel mouse, turning it
+/// into device type 4.
+const FIVE_BUTTON_SEQUENCE: [u8; 3] = [200, 200, 80];
+
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+/// Always set in the first byte of a packet, used to find its start.
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+/// In the fourth byte of a five button mouse.
+const BACK_BUTTON: u8 = 1 << 4;
+const FORWARD_BUTTON: u8 = 1 << 5;
+
+static MOUSE_PACKET_SENDER: OnceCell<Sender<u8>> = OnceCell::uninit();
 
+/// Called by the mouse interrupt handler
+///
+/// Must not block or allocate.
 pub(crate) fn add_mouse_packet(packet: u8) {
-    if let Ok(queue) = MOUSE_PACKET_QUEUE.try_get() {
-        if let Ok(_) = queue.push(packet) {
-            WAKER.wake();
+    if let Ok(sender) = MOUSE_PACKET_SENDER.try_get() {
+        let _ = sender.try_send(packet);
+    }
+}
+
+/// Called by `ps2::init` with the keyboard port disabled
+///
+/// Unlocks as many IntelliMouse extensions as the mouse has and starts its
+/// packets, returning what it turned out to be.
+pub(crate) fn init(controller: &mut Controller) -> Result<DeviceType, Ps2Error> {
+    controller.device_command(Device::Mouse, SET_DEFAULTS)?;
+    let mut device_type = controller.identify(Device::Mouse)?;
+    for sequence in [WHEEL_SEQUENCE, FIVE_BUTTON_SEQUENCE] {
+        for rate in sequence {
+            controller.device_command_data(Device::Mouse, SET_SAMPLE_RATE, rate)?;
+        }
+        device_type = controller.identify(Device::Mouse)?;
+        if device_type != DeviceType::WheelMouse {
+            break;
         }
     }
+    // the sequences leave the rate at 80 samples a second
+    controller.device_command(Device::Mouse, ps2::ENABLE_SCANNING)?;
+    Ok(device_type)
 }
 
-pub(crate) fn init() {
-    let mut mouse = MOUSE.lock();
-    mouse.init().expect("failed to initialize mouse");
-    mouse.set_on_complete(on_complete);
+/// Creates the channel the mouse interrupt handler sends packets to.
+pub fn mouse_packet_receiver() -> Receiver<u8> {
+    // the mouse sends up to 80 packets of 4 bytes a second, this will
+    // overflow quickly if the queue is set too low
+    let (sender, receiver) = mpsc::channel(500);
+    MOUSE_PACKET_SENDER.try_init_once(|| sender)
+        .expect("mouse_packet_receiver should only be called once");
+    receiver
 }
 
-pub struct MousePacketStream {
-    _private: (),
+/// Assembles packets from the bytes the mouse sends, one at a time.
+struct PacketDecoder {
+    device_type: DeviceType,
+    bytes: [u8; 4],
+    len: usize,
+    /// Buttons held as of the last packet, in the order of `MouseButton`.
+    buttons: [bool; 5],
 }
 
-impl MousePacketStream {
-    pub fn new() -> MousePacketStream {
-        // Default mouse settings generate packets at 100 packets per second/
-        // This will overflow quickly if the queue is set too low.
-        MOUSE_PACKET_QUEUE
-            .try_init_once(|| ArrayQueue::new(500))
-            .expect("MousePacketStream::new should only be called once");
-        MousePacketStream { _private: () }
+impl PacketDecoder {
+    fn new(device_type: DeviceType) -> Self {
+        PacketDecoder { device_type, bytes: [0; 4], len: 0, buttons: [false; 5] }
     }
-}
 
-impl Stream for MousePacketStream {
-    type Item = u8;
+    fn packet_size(&self) -> usize {
+        match self.device_type {
+            DeviceType::WheelMouse | DeviceType::FiveButtonMouse => 4,
+            _ => 3
+        }
+    }
+
+    /// Passes the events of a completed packet to `emit`.
+    fn add_byte(&mut self, byte: u8, mut emit: impl FnMut(MouseEvent)) {
+        // a lost byte would shift every following packet
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            return;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_size() {
+            return;
+        }
+        self.len = 0;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = MOUSE_PACKET_QUEUE
-            .try_get()
-            .expect("mouse state queue not initialized");
+        let [flags, x, y, extra] = self.bytes;
+        if flags & (X_OVERFLOW | Y_OVERFLOW) == 0 {
+            // 9 bit two's complement, the sign bits are in the first byte
+            let dx = x as i16 - if flags & X_SIGN != 0 { 0x100 } else { 0 };
+            let dy = y as i16 - if flags & Y_SIGN != 0 { 0x100 } else { 0 };
+            if dx != 0 || dy != 0 {
+                emit(MouseEvent::Move { dx, dy });
+            }
+        }
 
-        if let Some(packet) = queue.pop() {
-            return Poll::Ready(Some(packet));
+        let (lines, back, forward) = match self.device_type {
+            DeviceType::WheelMouse => (extra as i8, false, false),
+            // the low nibble is a 4 bit two's complement
+            DeviceType::FiveButtonMouse => (((extra << 4) as i8) >> 4, extra & BACK_BUTTON != 0, extra & FORWARD_BUTTON != 0),
+            _ => (0, false, false)
+        };
+        if lines != 0 {
+            emit(MouseEvent::Scroll { lines });
         }
 
-        WAKER.register(&cx.waker());
-        match queue.pop() {
-            Some(packet) => {
-                WAKER.take();
-                Poll::Ready(Some(packet))
+        let buttons = [
+            (MouseButton::Left, flags & LEFT_BUTTON != 0),
+            (MouseButton::Right, flags & RIGHT_BUTTON != 0),
+            (MouseButton::Middle, flags & MIDDLE_BUTTON != 0),
+            (MouseButton::Back, back),
+            (MouseButton::Forward, forward),
+        ];
+        for ((button, pressed), held) in buttons.into_iter().zip(&mut self.buttons) {
+            if pressed != *held {
+                *held = pressed;
+                emit(MouseEvent::Button { button, pressed });
             }
-            None => Poll::Pending,
         }
     }
 }
 
-fn on_complete(mouse_state: MouseState) {
-    // log::trace!("{:?}", mouse_state);
-    if mouse_state.moved() {
-        crate::vga_buffer::cursor_position_delta(mouse_state.get_x(), mouse_state.get_y());
+/// Decodes mouse packets into events for `input`.
+pub async fn process_packets() {
+    let mut packets = mouse_packet_receiver();
+    let device_type = ps2::device_type(Device::Mouse).unwrap_or(DeviceType::Mouse);
+    let mut decoder = PacketDecoder::new(device_type);
+
+    while let Some(packet) = packets.recv().await {
+        decoder.add_byte(packet, |event| input::dispatch(InputEvent::Mouse(event)));
     }
 }
 
-pub async fn process_packets() {
-    let mut mouse = MOUSE.lock();
-    let mut packets = MousePacketStream::new();
+#[test_case]
+fn test_decode_five_button_packet() {
+    use alloc::vec::Vec;
 
-    while let Some(packet) = packets.next().await {
-        mouse.process_packet(packet);
+    let mut decoder = PacketDecoder::new(DeviceType::FiveButtonMouse);
+    let mut events = Vec::new();
+    // stray byte, then left button held, moved by (-2, 3), scrolled by -1
+    // with the back button held
+    for byte in [0x00, 0x19, 0xFE, 0x03, 0x1F] {
+        decoder.add_byte(byte, |event| events.push(event));
     }
+    assert_eq!(events, [
+        MouseEvent::Move { dx: -2, dy: 3 },
+        MouseEvent::Scroll { lines: -1 },
+        MouseEvent::Button { button: MouseButton::Left, pressed: true },
+        MouseEvent::Button { button: MouseButton::Back, pressed: true },
+    ]);
 }
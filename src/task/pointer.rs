@@ -0,0 +1,50 @@
+//! Mouse pointer on the text screen. Dragging with the left button selects
+//! text, which is copied to the clipboard when the button is released.
+
+use crate::{clipboard, vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH}};
+use super::input::{self, InputEvent, MouseButton, MouseEvent};
+
+/// Mouse counts to cross a cell, which is about twice as tall as it's wide.
+const COUNTS_PER_COLUMN: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+
+/// Cell the pointer is on, for a position in mouse counts.
+fn cell((x, y): (i32, i32)) -> (usize, usize) {
+    ((x / COUNTS_PER_COLUMN) as usize, (y / COUNTS_PER_ROW) as usize)
+}
+
+/// Moves the pointer and tracks the selection, whoever has the input focus.
+pub async fn track_pointer() {
+    let mut events = input::subscribe();
+    // the pointer shows up in the middle of the screen once the mouse is used
+    let mut position = (BUFFER_WIDTH as i32 * COUNTS_PER_COLUMN / 2, BUFFER_HEIGHT as i32 * COUNTS_PER_ROW / 2);
+    let mut anchor = None;
+
+    while let Some(event) = events.recv().await {
+        match event {
+            InputEvent::Mouse(MouseEvent::Move { dx, dy }) => {
+                position.0 = (position.0 + dx as i32).clamp(0, BUFFER_WIDTH as i32 * COUNTS_PER_COLUMN - 1);
+                position.1 = (position.1 - dy as i32).clamp(0, BUFFER_HEIGHT as i32 * COUNTS_PER_ROW - 1);
+                let (x, y) = cell(position);
+                vga_buffer::set_pointer(x, y);
+                if let Some(anchor) = anchor {
+                    vga_buffer::select(anchor, (x, y));
+                }
+            },
+            InputEvent::Mouse(MouseEvent::Button { button: MouseButton::Left, pressed: true }) => {
+                let (x, y) = cell(position);
+                vga_buffer::set_pointer(x, y);
+                vga_buffer::clear_selection();
+                anchor = Some((x, y));
+            },
+            InputEvent::Mouse(MouseEvent::Button { button: MouseButton::Left, pressed: false }) => {
+                anchor = None;
+                let text = vga_buffer::selected_text();
+                if !text.is_empty() {
+                    clipboard::copy(text);
+                }
+            },
+            _ => {}
+        }
+    }
+}
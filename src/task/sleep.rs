@@ -1,52 +1,81 @@
-use core::{pin::Pin, task::{Poll, Context}};
-use futures_util::{future::Future, task::AtomicWaker};
-use spin::Mutex;
+use core::{pin::Pin, task::{Poll, Context, Waker}};
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::time::Duration;
+use futures_util::future::Future;
+use alloc::collections::BTreeMap;
+use crate::{smp::{self, percpu}, spinlock::SpinLock, time::Instant};
 
-static TIMER: Mutex<Duration> = Mutex::new(Duration::from_secs(0));
-static WAKER: AtomicWaker = AtomicWaker::new();
+/// Pending sleeps ordered by deadline, the `u64` disambiguates equal deadlines.
+static SLEEPERS: SpinLock<BTreeMap<(Instant, u64), Waker>> = SpinLock::new(BTreeMap::new());
 
-/// Called by the timer interrupt handler
+/// Called by the executor before running ready tasks, and by the timer
+/// interrupt handler for the CPUs that have no timer
 ///
-/// Must not block or allocate.
-pub(crate) fn sleep_task_tick(rate: Duration) {
-    let mut timer = TIMER.lock();
-    if *timer > Duration::from_secs(0) {
-        *timer = (*timer).checked_sub(rate).unwrap_or(Duration::from_secs(0));
-    } else {
-        WAKER.wake();
+/// Wakes every sleep whose deadline has passed.
+pub(crate) fn wake_expired_sleepers() {
+    let now = Instant::now();
+    let mut sleepers = SLEEPERS.lock();
+    while let Some(entry) = sleepers.first_entry() {
+        if entry.key().0 > now {
+            break;
+        }
+        entry.remove().wake();
     }
 }
 
+/// Earliest deadline among the pending sleeps.
+pub(crate) fn next_deadline() -> Option<Instant> {
+    SLEEPERS.lock().keys().next().map(|(deadline, _)| *deadline)
+}
+
 pub struct Sleep {
-    _private: (),
+    deadline: Instant,
+    id: u64,
 }
 
 impl Sleep {
     pub fn new(duration: Duration) -> Self {
-        setup_sleep(duration);
-        Sleep { _private: () }
+        Sleep::until(Instant::now() + duration)
     }
-}
 
-fn setup_sleep(duration: Duration) {
-    let mut timer = TIMER.lock();
-    *timer = duration;
+    pub fn until(deadline: Instant) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Sleep {
+            deadline,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
 }
 
 impl Future for Sleep {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
-        if *TIMER.lock() == Duration::from_secs(0) {
+        if Instant::now() >= self.deadline {
+            SLEEPERS.lock().remove(&(self.deadline, self.id));
             return Poll::Ready(());
         }
 
-        WAKER.register(&cx.waker());
-        if *TIMER.lock() == Duration::from_secs(0) {
-            Poll::Ready(())
-        } else {
-            Poll::Pending
+        let key = (self.deadline, self.id);
+        let mut sleepers = SLEEPERS.lock();
+        let inserted = sleepers.insert(key, cx.waker().clone()).is_none();
+        let earliest = sleepers.keys().next() == Some(&key);
+        drop(sleepers);
+        // the bootstrap processor armed its timer for the earliest deadline
+        // it knew of before halting, it has to arm it again
+        if inserted && earliest && !percpu::current().is_bootstrap() {
+            smp::wake_up(0);
         }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        SLEEPERS.lock().remove(&(self.deadline, self.id));
     }
 }
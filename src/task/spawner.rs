@@ -1,30 +1,58 @@
-use super::Task;
-use alloc::sync::Arc;
-use crossbeam_queue::ArrayQueue;
-use conquer_once::spin::OnceCell;
+use super::{Priority, Task, executor, join::{self, JoinHandle}};
+use alloc::borrow::Cow;
+use core::future::Future;
+use crossbeam_queue::SegQueue;
 
-pub(crate) static SPAWNER: OnceCell<Spawner> = OnceCell::uninit();
+/// Lock free, so tasks can be spawned from any CPU.
+pub(crate) static SPAWNER: Spawner = Spawner::new();
 
-pub fn spawn(task: Task) {
-    SPAWNER.get().expect("Task spawner not initialized.").spawn(task);
+/// Spawns `future` on the executor, returning a handle to await its output.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_with_priority(future, Priority::default())
 }
 
+pub fn spawn_with_priority<F>(future: F, priority: Priority) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_named("unnamed", priority, future)
+}
+
+/// Spawns `future` under `name`, which is shown by the `ps` command.
+pub fn spawn_named<F>(
+    name: impl Into<Cow<'static, str>>,
+    priority: Priority,
+    future: F
+) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (task, join_handle) = join::joinable(name.into(), priority, future);
+    SPAWNER.spawn(task);
+    join_handle
+}
+
+/// New tasks wait in the shared queue until an executor on any CPU takes
+/// them in.
 pub(crate) struct Spawner {
-    pub shared_task_queue: Arc<ArrayQueue<Task>>
+    pub shared_task_queue: SegQueue<Task>
 }
 
 impl Spawner {
-    pub(crate) fn new() -> Self {
+    const fn new() -> Self {
         Spawner {
-            shared_task_queue: Arc::new(ArrayQueue::new(100))
+            shared_task_queue: SegQueue::new()
         }
     }
 
-    pub(crate) fn clone_shared_task_queue(&self) -> Arc<ArrayQueue<Task>> {
-        self.shared_task_queue.clone()
-    }
-
     fn spawn(&self, task: Task) {
-        self.shared_task_queue.push(task).expect("queue full");
+        self.shared_task_queue.push(task);
+        executor::wake_idle_cpu();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,52 @@
+//! Async synchronization primitives for kernel tasks.
+//!
+//! Everything that can be signaled (sending on a channel, releasing permits,
+//! notifying) never blocks nor allocates, so it's safe to do from interrupt
+//! handlers. Waiting is only possible from tasks.
+
+pub mod mpsc;
+pub mod oneshot;
+mod mutex;
+mod notify;
+mod semaphore;
+mod wait_queue;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::Notify;
+pub use semaphore::{Semaphore, SemaphorePermit};
+
+/// Counts how many times the waker it hands out was woken.
+#[cfg(test)]
+struct WakeCounter(core::sync::atomic::AtomicUsize);
+
+#[cfg(test)]
+impl WakeCounter {
+    fn new() -> alloc::sync::Arc<Self> {
+        alloc::sync::Arc::new(WakeCounter(core::sync::atomic::AtomicUsize::new(0)))
+    }
+
+    fn count(&self) -> usize {
+        self.0.load(core::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+impl alloc::task::Wake for WakeCounter {
+    fn wake(self: alloc::sync::Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &alloc::sync::Arc<Self>) {
+        self.0.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Polls `future` once with a waker that counts its wakeups in `counter`.
+#[cfg(test)]
+fn poll_with<F: core::future::Future>(
+    future: core::pin::Pin<&mut F>,
+    counter: &alloc::sync::Arc<WakeCounter>
+) -> core::task::Poll<F::Output> {
+    let waker = core::task::Waker::from(counter.clone());
+    future.poll(&mut core::task::Context::from_waker(&waker))
+}
@@ -0,0 +1,267 @@
+//! Bounded multi-producer, single-consumer channel.
+//!
+//! `Sender::try_send` never blocks nor allocates, so interrupt handlers can
+//! use it to hand data to a task.
+
+use super::wait_queue::WaitQueue;
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll}
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+struct Shared<T> {
+    queue: ArrayQueue<T>,
+    receiver_waker: AtomicWaker,
+    waiting_senders: WaitQueue,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+}
+
+/// Creates a channel buffering up to `capacity` values.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: ArrayQueue::new(capacity),
+        receiver_waker: AtomicWaker::new(),
+        waiting_senders: WaitQueue::new(),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity, the value is given back.
+    Full(T),
+    /// The receiver was dropped, the value is given back.
+    Closed(T),
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => write!(f, "Full(..)"),
+            Self::Closed(_) => write!(f, "Closed(..)"),
+        }
+    }
+}
+
+/// The receiver was dropped, the value is given back.
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SendError(..)")
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if !self.shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+        match self.shared.queue.push(value) {
+            Ok(()) => {
+                self.shared.receiver_waker.wake();
+                Ok(())
+            },
+            Err(value) => Err(TrySendError::Full(value))
+        }
+    }
+
+    /// Sends `value`, waiting for capacity if the channel is full.
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        SendFuture {
+            sender: self,
+            value: Some(value),
+            waiter: None,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.shared.receiver_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // last sender gone, let the receiver see the channel is closed
+            self.shared.receiver_waker.wake();
+        }
+    }
+}
+
+/// Future returned by `Sender::send`.
+pub struct SendFuture<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    waiter: Option<u64>,
+}
+
+// the value is never pinned
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let shared = &this.sender.shared;
+        let value = &mut this.value;
+
+        shared.waiting_senders.poll_acquire(&mut this.waiter, cx, || {
+            match this.sender.try_send(value.take()?) {
+                Ok(()) => Some(Ok(())),
+                Err(TrySendError::Closed(value)) => Some(Err(SendError(value))),
+                Err(TrySendError::Full(full)) => {
+                    *value = Some(full);
+                    None
+                }
+            }
+        })
+    }
+}
+
+impl<T> Drop for SendFuture<'_, T> {
+    fn drop(&mut self) {
+        if self.sender.shared.waiting_senders.cancel(&mut self.waiter) {
+            self.sender.shared.waiting_senders.notify_one();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Returns a value if one is buffered, without waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        let value = self.shared.queue.pop();
+        if value.is_some() {
+            self.shared.waiting_senders.notify_one();
+        }
+        value
+    }
+
+    /// Receives the next value, `None` once all senders are dropped and the
+    /// buffer is empty.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        // fast path
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(Some(value));
+        }
+
+        self.shared.receiver_waker.register(cx.waker());
+        match self.try_recv() {
+            Some(value) => {
+                self.shared.receiver_waker.take();
+                Poll::Ready(Some(value))
+            },
+            None if self.shared.senders.load(Ordering::Acquire) == 0 => Poll::Ready(None),
+            None => Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+        self.shared.waiting_senders.notify_all();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.poll_recv(cx)
+    }
+}
+
+/// Future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[test_case]
+fn test_channel_is_bounded() {
+    use super::{poll_with, WakeCounter};
+    let (sender, mut receiver) = channel(2);
+    let counter = WakeCounter::new();
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.try_send(2), Ok(()));
+    assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+
+    let mut send = core::pin::pin!(sender.send(3));
+    assert!(poll_with(send.as_mut(), &counter).is_pending());
+    assert_eq!(receiver.try_recv(), Some(1));
+    assert_eq!(counter.count(), 1);
+    assert!(matches!(poll_with(send, &counter), Poll::Ready(Ok(()))));
+    assert_eq!(receiver.try_recv(), Some(2));
+    assert_eq!(receiver.try_recv(), Some(3));
+    assert_eq!(receiver.try_recv(), None);
+}
+
+#[test_case]
+fn test_dropping_receiver_closes_channel() {
+    use super::{poll_with, WakeCounter};
+    let (sender, receiver) = channel(1);
+    let counter = WakeCounter::new();
+    sender.try_send(1).unwrap();
+    let mut send = core::pin::pin!(sender.send(2));
+    assert!(poll_with(send.as_mut(), &counter).is_pending());
+
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(counter.count(), 1);
+    assert!(matches!(poll_with(send, &counter), Poll::Ready(Err(SendError(2)))));
+    assert_eq!(sender.try_send(3), Err(TrySendError::Closed(3)));
+}
+
+#[test_case]
+fn test_dropping_senders_ends_receiver() {
+    use super::{poll_with, WakeCounter};
+    let (sender, mut receiver) = channel(2);
+    let counter = WakeCounter::new();
+    let other = sender.clone();
+    sender.try_send(1).unwrap();
+    drop(sender);
+    assert!(matches!(poll_with(core::pin::pin!(receiver.recv()), &counter), Poll::Ready(Some(1))));
+    assert!(poll_with(core::pin::pin!(receiver.recv()), &counter).is_pending());
+
+    drop(other);
+    assert_eq!(counter.count(), 1);
+    assert!(matches!(poll_with(core::pin::pin!(receiver.recv()), &counter), Poll::Ready(None)));
+}
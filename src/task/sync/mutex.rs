@@ -0,0 +1,156 @@
+use super::wait_queue::WaitQueue;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll}
+};
+
+/// A mutual exclusion lock for tasks.
+///
+/// Acquiring it is a future, so a task waiting for the lock yields to the
+/// executor instead of spinning, and the guard can be held across `.await`
+/// points without blocking other tasks from running.
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            waiter: None,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mutex").field("locked", &self.locked.load(Ordering::Relaxed)).finish()
+    }
+}
+
+/// Future returned by `Mutex::lock`.
+pub struct Lock<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    waiter: Option<u64>,
+}
+
+impl<'a, T: ?Sized> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<MutexGuard<'a, T>> {
+        let mutex = self.mutex;
+        mutex.waiters.poll_acquire(&mut self.waiter, cx, || mutex.try_lock())
+    }
+}
+
+impl<T: ?Sized> Drop for Lock<'_, T> {
+    fn drop(&mut self) {
+        if self.mutex.waiters.cancel(&mut self.waiter) {
+            // the lock was handed to this waiter, give it to the next one
+            self.mutex.waiters.notify_one();
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.notify_one();
+    }
+}
+
+#[test_case]
+fn test_lock_is_handed_out_in_order() {
+    use super::{poll_with, WakeCounter};
+    let mutex = Mutex::new(0);
+    let (first_woken, second_woken) = (WakeCounter::new(), WakeCounter::new());
+    let guard = mutex.try_lock().unwrap();
+    let mut first = core::pin::pin!(mutex.lock());
+    let mut second = core::pin::pin!(mutex.lock());
+    assert!(poll_with(first.as_mut(), &first_woken).is_pending());
+    assert!(poll_with(second.as_mut(), &second_woken).is_pending());
+
+    drop(guard);
+    assert_eq!((first_woken.count(), second_woken.count()), (1, 0));
+    let Poll::Ready(mut guard) = poll_with(first, &first_woken) else {
+        panic!("first waiter didn't get the lock");
+    };
+    *guard += 1;
+    assert!(poll_with(second.as_mut(), &second_woken).is_pending());
+
+    drop(guard);
+    assert_eq!(second_woken.count(), 1);
+    assert!(matches!(poll_with(second, &second_woken), Poll::Ready(guard) if *guard == 1));
+}
+
+#[test_case]
+fn test_dropped_lock_future_hands_lock_on() {
+    use super::{poll_with, WakeCounter};
+    let mutex = Mutex::new(());
+    let (first_woken, second_woken) = (WakeCounter::new(), WakeCounter::new());
+    let guard = mutex.try_lock().unwrap();
+    let mut first = alloc::boxed::Box::pin(mutex.lock());
+    let mut second = core::pin::pin!(mutex.lock());
+    assert!(poll_with(first.as_mut(), &first_woken).is_pending());
+    assert!(poll_with(second.as_mut(), &second_woken).is_pending());
+
+    drop(guard);
+    // cancelled after being woken, before taking the lock
+    drop(first);
+    assert_eq!(second_woken.count(), 1);
+    assert!(poll_with(second, &second_woken).is_ready());
+}
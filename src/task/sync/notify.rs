@@ -0,0 +1,133 @@
+use super::wait_queue::WaitQueue;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll}
+};
+
+/// Notifies one or all waiting tasks of an event.
+///
+/// A `notify_one` without waiters is remembered, so the next `notified()`
+/// completes right away. Notifying can be done from interrupt handlers.
+pub struct Notify {
+    permit: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            permit: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Wakes the oldest waiting task, or stores a permit for the next one if
+    /// no task is waiting.
+    pub fn notify_one(&self) {
+        self.waiters.notify_one_or(|| self.permit.store(true, Ordering::Release));
+    }
+
+    /// Wakes every task currently waiting, without storing a permit.
+    pub fn notify_waiters(&self) {
+        self.waiters.notify_all();
+    }
+
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+    }
+}
+
+/// Future returned by `Notify::notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let notify = self.notify;
+        if self.waiter.is_some() && notify.waiters.poll_wait(&mut self.waiter, cx.waker()) {
+            // woken by notify_one or notify_waiters, which didn't store a
+            // permit, any permit there is belongs to a later notify_one
+            return Poll::Ready(());
+        }
+        notify.waiters.poll_acquire(&mut self.waiter, cx, || {
+            notify.permit.swap(false, Ordering::Acquire).then(|| ())
+        })
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if self.notify.waiters.cancel(&mut self.waiter) {
+            self.notify.notify_one();
+        }
+    }
+}
+
+#[test_case]
+fn test_notify_one_stores_one_permit() {
+    use super::{poll_with, WakeCounter};
+    let notify = Notify::new();
+    let counter = WakeCounter::new();
+    notify.notify_one();
+    notify.notify_one();
+    assert!(poll_with(core::pin::pin!(notify.notified()), &counter).is_ready());
+    assert!(poll_with(core::pin::pin!(notify.notified()), &counter).is_pending());
+}
+
+#[test_case]
+fn test_notify_one_releases_one_waiter() {
+    use super::{poll_with, WakeCounter};
+    let notify = Notify::new();
+    let (first_woken, second_woken) = (WakeCounter::new(), WakeCounter::new());
+    let mut first = core::pin::pin!(notify.notified());
+    let mut second = core::pin::pin!(notify.notified());
+    assert!(poll_with(first.as_mut(), &first_woken).is_pending());
+    assert!(poll_with(second.as_mut(), &second_woken).is_pending());
+
+    notify.notify_one();
+    assert_eq!((first_woken.count(), second_woken.count()), (1, 0));
+    assert!(poll_with(first, &first_woken).is_ready());
+    assert!(poll_with(second.as_mut(), &second_woken).is_pending());
+    // the notification went to a waiter, so no permit is left over
+    assert!(poll_with(core::pin::pin!(notify.notified()), &first_woken).is_pending());
+}
+
+#[test_case]
+fn test_woken_waiter_keeps_later_permit() {
+    use super::{poll_with, WakeCounter};
+    let notify = Notify::new();
+    let counter = WakeCounter::new();
+    let mut waiter = core::pin::pin!(notify.notified());
+    assert!(poll_with(waiter.as_mut(), &counter).is_pending());
+
+    notify.notify_waiters();
+    // the only waiter is already notified, so this stores a permit
+    notify.notify_one();
+    assert!(poll_with(waiter, &counter).is_ready());
+    assert!(poll_with(core::pin::pin!(notify.notified()), &counter).is_ready());
+}
+
+#[test_case]
+fn test_dropped_waiter_passes_notification_on() {
+    use super::{poll_with, WakeCounter};
+    let notify = Notify::new();
+    let (first_woken, second_woken) = (WakeCounter::new(), WakeCounter::new());
+    let mut first = alloc::boxed::Box::pin(notify.notified());
+    let mut second = core::pin::pin!(notify.notified());
+    assert!(poll_with(first.as_mut(), &first_woken).is_pending());
+    assert!(poll_with(second.as_mut(), &second_woken).is_pending());
+
+    notify.notify_one();
+    drop(first);
+    assert_eq!(second_woken.count(), 1);
+    assert!(poll_with(second, &second_woken).is_ready());
+}
@@ -0,0 +1,119 @@
+//! Channel sending a single value, e.g. the reply to a request.
+//!
+//! `Sender::send` doesn't block, so the value can come from an interrupt
+//! handler.
+
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll}
+};
+use futures_util::task::AtomicWaker;
+use crate::spinlock::SpinLock;
+
+struct Shared<T> {
+    value: SpinLock<Option<T>>,
+    /// Set once the sender sent a value or was dropped.
+    complete: AtomicBool,
+    receiver_alive: AtomicBool,
+    waker: AtomicWaker,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        value: SpinLock::new(None),
+        complete: AtomicBool::new(false),
+        receiver_alive: AtomicBool::new(true),
+        waker: AtomicWaker::new(),
+    });
+    (Sender { shared: Some(shared.clone()) }, Receiver { shared })
+}
+
+/// The sender was dropped without sending a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sender dropped without sending")
+    }
+}
+
+pub struct Sender<T> {
+    shared: Option<Arc<Shared<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, giving it back if the receiver was dropped.
+    pub fn send(mut self, value: T) -> Result<(), T> {
+        let shared = self.shared.take().expect("oneshot value already sent");
+        if !shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(value);
+        }
+        *shared.value.lock() = Some(value);
+        shared.complete.store(true, Ordering::Release);
+        shared.waker.wake();
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.as_ref().map_or(true, |shared| !shared.receiver_alive.load(Ordering::Acquire))
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if let Some(shared) = self.shared.take() {
+            shared.complete.store(true, Ordering::Release);
+            shared.waker.wake();
+        }
+    }
+}
+
+/// Future resolving to the sent value.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    fn take_value(&self) -> Option<Result<T, RecvError>> {
+        if !self.shared.complete.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = self.shared.value.lock().take();
+        Some(value.ok_or(RecvError))
+    }
+
+    pub fn try_recv(&mut self) -> Option<Result<T, RecvError>> {
+        self.take_value()
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // fast path
+        if let Some(result) = self.take_value() {
+            return Poll::Ready(result);
+        }
+
+        self.shared.waker.register(cx.waker());
+        match self.take_value() {
+            Some(result) => {
+                self.shared.waker.take();
+                Poll::Ready(result)
+            },
+            None => Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,126 @@
+use super::wait_queue::WaitQueue;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll}
+};
+
+/// Counts permits that tasks acquire asynchronously.
+///
+/// `add_permits` can be called from interrupt handlers.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Acquire)
+    }
+
+    pub fn add_permits(&self, permits: usize) {
+        self.permits.fetch_add(permits, Ordering::Release);
+        for _ in 0..permits {
+            if !self.waiters.notify_one() {
+                break;
+            }
+        }
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| permits.checked_sub(1))
+            .ok()
+            .map(|_| SemaphorePermit { semaphore: self })
+    }
+
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            waiter: None,
+        }
+    }
+}
+
+/// Future returned by `Semaphore::acquire`.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    waiter: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let semaphore = self.semaphore;
+        semaphore.waiters.poll_acquire(&mut self.waiter, cx, || semaphore.try_acquire())
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if self.semaphore.waiters.cancel(&mut self.waiter) {
+            self.semaphore.waiters.notify_one();
+        }
+    }
+}
+
+/// A permit returned to the semaphore when dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    /// Consumes the permit without returning it to the semaphore.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
+
+#[test_case]
+fn test_permits_are_counted() {
+    let semaphore = Semaphore::new(2);
+    let first = semaphore.try_acquire().unwrap();
+    let second = semaphore.try_acquire().unwrap();
+    assert!(semaphore.try_acquire().is_none());
+    assert_eq!(semaphore.available_permits(), 0);
+
+    drop(first);
+    assert_eq!(semaphore.available_permits(), 1);
+    second.forget();
+    assert_eq!(semaphore.available_permits(), 1);
+    semaphore.add_permits(2);
+    assert_eq!(semaphore.available_permits(), 3);
+}
+
+#[test_case]
+fn test_released_permit_goes_to_waiter() {
+    use super::{poll_with, WakeCounter};
+    let semaphore = Semaphore::new(1);
+    let counter = WakeCounter::new();
+    let permit = semaphore.try_acquire().unwrap();
+    let mut acquire = core::pin::pin!(semaphore.acquire());
+    assert!(poll_with(acquire.as_mut(), &counter).is_pending());
+
+    drop(permit);
+    assert_eq!(counter.count(), 1);
+    match poll_with(acquire, &counter) {
+        Poll::Ready(permit) => permit.forget(),
+        Poll::Pending => panic!("released permit wasn't acquired"),
+    }
+    assert_eq!(semaphore.available_permits(), 0);
+}
@@ -0,0 +1,128 @@
+use alloc::collections::BTreeMap;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker}
+};
+use crate::spinlock::SpinLock;
+
+struct Waiter {
+    waker: Waker,
+    notified: bool,
+}
+
+/// Tasks waiting for a resource, woken in FIFO order.
+///
+/// Notifying only marks and wakes a waiter, the waiter removes itself from
+/// the queue when polled, so notifying never allocates nor frees memory. The
+/// lock disables interrupts, which lets interrupt handlers notify.
+pub(crate) struct WaitQueue {
+    waiters: SpinLock<BTreeMap<u64, Waiter>>,
+    next_id: AtomicU64,
+}
+
+impl WaitQueue {
+    pub(crate) const fn new() -> Self {
+        WaitQueue {
+            waiters: SpinLock::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Registers the waiter identified by `id`, assigning an id on the first
+    /// call. Returns true, removing the waiter, if it has been notified.
+    pub(crate) fn poll_wait(&self, id: &mut Option<u64>, waker: &Waker) -> bool {
+        let mut waiters = self.waiters.lock();
+        match id.and_then(|key| waiters.get_mut(&key).map(|waiter| (key, waiter))) {
+            Some((key, waiter)) if waiter.notified => {
+                waiters.remove(&key);
+                *id = None;
+                true
+            },
+            Some((_, waiter)) => {
+                if !waiter.waker.will_wake(waker) {
+                    waiter.waker = waker.clone();
+                }
+                false
+            },
+            None => {
+                let key = self.next_id.fetch_add(1, Ordering::Relaxed);
+                waiters.insert(key, Waiter {
+                    waker: waker.clone(),
+                    notified: false,
+                });
+                *id = Some(key);
+                false
+            }
+        }
+    }
+
+    /// Removes the waiter identified by `id`, returning whether it had been
+    /// notified, in which case the caller should pass the notification on.
+    pub(crate) fn cancel(&self, id: &mut Option<u64>) -> bool {
+        match id.take() {
+            Some(key) => self.waiters.lock().remove(&key).map_or(false, |waiter| waiter.notified),
+            None => false
+        }
+    }
+
+    /// Wakes the oldest waiter that wasn't notified yet, returns false if
+    /// there is none.
+    pub(crate) fn notify_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        match waiters.values_mut().find(|waiter| !waiter.notified) {
+            Some(waiter) => {
+                waiter.notified = true;
+                waiter.waker.wake_by_ref();
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Like `notify_one`, but runs `no_waiter` when there is nobody to wake,
+    /// under the lock, so a waiter can't register in between.
+    pub(crate) fn notify_one_or(&self, no_waiter: impl FnOnce()) {
+        let mut waiters = self.waiters.lock();
+        match waiters.values_mut().find(|waiter| !waiter.notified) {
+            Some(waiter) => {
+                waiter.notified = true;
+                waiter.waker.wake_by_ref();
+            },
+            None => no_waiter()
+        }
+    }
+
+    pub(crate) fn notify_all(&self) {
+        for waiter in self.waiters.lock().values_mut().filter(|waiter| !waiter.notified) {
+            waiter.notified = true;
+            waiter.waker.wake_by_ref();
+        }
+    }
+
+    /// Polls `try_acquire` until it succeeds, waiting in the queue in between.
+    pub(crate) fn poll_acquire<R>(
+        &self,
+        id: &mut Option<u64>,
+        cx: &mut Context,
+        mut try_acquire: impl FnMut() -> Option<R>
+    ) -> Poll<R> {
+        loop {
+            if let Some(resource) = try_acquire() {
+                self.cancel(id);
+                return Poll::Ready(resource);
+            }
+
+            if !self.poll_wait(id, cx.waker()) {
+                // the resource may have been released before the waiter was
+                // registered, in which case nobody is going to notify it
+                return match try_acquire() {
+                    Some(resource) => {
+                        self.cancel(id);
+                        Poll::Ready(resource)
+                    },
+                    None => Poll::Pending
+                };
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use super::{TaskId, TaskInfo};
+use alloc::sync::Arc;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration
+};
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::{smp::{percpu, MAX_CPUS}, spinlock::SpinLock, time::Instant};
+
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Task being polled on each CPU, `None` while its executor isn't polling.
+static CURRENT_TASKS: [SpinLock<Option<CurrentTask>>; MAX_CPUS] = [const { SpinLock::new(None) }; MAX_CPUS];
+static THRESHOLD_NANOS: AtomicU64 = AtomicU64::new(DEFAULT_THRESHOLD.as_nanos() as u64);
+
+struct CurrentTask {
+    id: TaskId,
+    info: Arc<TaskInfo>,
+    poll_start_nanos: u64,
+    /// Set once the poll has been reported, so it's reported only once.
+    reported: bool,
+}
+
+/// Sets how long a single poll may run before the watchdog reports it.
+pub fn set_threshold(threshold: Duration) {
+    THRESHOLD_NANOS.store(threshold.as_nanos() as u64, Ordering::Relaxed);
+}
+
+pub fn threshold() -> Duration {
+    Duration::from_nanos(THRESHOLD_NANOS.load(Ordering::Relaxed))
+}
+
+/// Called by the executor right before polling a task
+pub(crate) fn poll_started(task_id: TaskId, info: &Arc<TaskInfo>) {
+    let current = CurrentTask {
+        id: task_id,
+        info: info.clone(),
+        poll_start_nanos: Instant::now().as_nanos(),
+        reported: false,
+    };
+    *CURRENT_TASKS[percpu::current().index()].lock() = Some(current);
+}
+
+/// Called by the executor right after polling a task
+pub(crate) fn poll_finished() {
+    // dropped outside of the lock, it may free the task's info
+    let _finished = CURRENT_TASKS[percpu::current().index()].lock().take();
+}
+
+/// Called by the timer interrupt handler
+///
+/// Reports the tasks that have been polled for longer than the threshold on
+/// any CPU, together with where the interrupted one on this CPU was. With the
+/// `watchdog-panic` feature it panics after printing a backtrace instead.
+///
+/// Must not block or allocate.
+pub(crate) fn check(stack_frame: &InterruptStackFrame) {
+    let this_cpu = percpu::current().index();
+    for (cpu, current) in CURRENT_TASKS.iter().enumerate() {
+        // taken while this CPU's executor was interrupted starting or
+        // finishing a poll, or while the other CPU is doing the same
+        let mut current = match current.try_lock() {
+            Some(current) => current,
+            None => continue,
+        };
+        let current = match current.as_mut() {
+            Some(current) => current,
+            None => continue,
+        };
+
+        let running = Duration::from_nanos(Instant::now().as_nanos().saturating_sub(current.poll_start_nanos));
+        if running < threshold() || current.reported {
+            continue;
+        }
+        current.reported = true;
+
+        let (task_id, name) = (current.id.0, &current.info.name);
+        if cpu == this_cpu {
+            log::warn!("watchdog: task {} '{}' has been running for {:?} without yielding, ip={:?}",
+                task_id, name, running, stack_frame.instruction_pointer);
+        } else {
+            log::warn!("watchdog: task {} '{}' has been running for {:?} without yielding on CPU {}",
+                task_id, name, running, cpu);
+        }
+
+        #[cfg(feature="watchdog-panic")]
+        {
+            if cpu == this_cpu {
+                print_backtrace();
+                panic!("watchdog: task {} '{}' is stuck at {:?}", task_id, name, stack_frame.instruction_pointer);
+            }
+            panic!("watchdog: task {} '{}' is stuck on CPU {}", task_id, name, cpu);
+        }
+    }
+}
+
+/// Walks the frame pointer chain, which starts at the interrupt handler whose
+/// return address is the interrupted instruction.
+///
+/// Relies on the kernel being built with `-C force-frame-pointers=yes`.
+#[cfg(feature="watchdog-panic")]
+fn print_backtrace() {
+    const MAX_FRAMES: usize = 32;
+
+    let mut rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+
+    log::error!("backtrace:");
+    for frame in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        // each frame starts with the caller's rbp followed by the return address
+        let (caller_rbp, return_address) = unsafe {
+            let frame_pointer = rbp as *const u64;
+            (*frame_pointer, *frame_pointer.add(1))
+        };
+        if return_address == 0 {
+            break;
+        }
+        log::error!("  {:>2}: {:#018x}", frame, return_address);
+        // callers live higher up the stack, anything else is a broken chain
+        if caller_rbp <= rbp {
+            break;
+        }
+        rbp = caller_rbp;
+    }
+}
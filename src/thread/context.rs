@@ -0,0 +1,62 @@
+use core::arch::global_asm;
+use x86_64::VirtAddr;
+
+// Callee-saved registers are pushed on the stack being switched away from,
+// everything else has already been saved by the caller.
+global_asm!(r#"
+.global thread_switch_context
+thread_switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global thread_trampoline
+thread_trampoline:
+    mov rdi, r12
+    and rsp, -16
+    call {entry}
+    ud2
+"#, entry = sym super::thread_entry);
+
+extern "C" {
+    /// Saves the current stack pointer to `old_rsp` and continues on the
+    /// stack at `new_rsp`.
+    #[link_name = "thread_switch_context"]
+    pub fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_trampoline();
+}
+
+/// Lays out a new stack so that switching to the returned stack pointer
+/// calls `thread_entry(arg)`.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// stack below `stack_top` is mapped and unused.
+pub unsafe fn init_stack(stack_top: VirtAddr, arg: u64) -> u64 {
+    // popped by `thread_switch_context`, a zero rbp ends frame pointer walks
+    let frame = [
+        0, // r15
+        0, // r14
+        0, // r13
+        arg, // r12
+        0, // rbx
+        0, // rbp
+        thread_trampoline as usize as u64,
+    ];
+    let rsp = stack_top.as_mut_ptr::<u64>().sub(frame.len());
+    for (i, value) in frame.iter().enumerate() {
+        rsp.add(i).write(*value);
+    }
+    rsp as u64
+}
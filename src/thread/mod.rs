@@ -0,0 +1,469 @@
+use alloc::{borrow::Cow, boxed::Box, collections::{BTreeMap, VecDeque}, sync::Arc, vec::Vec};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration
+};
+use x86_64::{instructions::interrupts, structures::paging::PhysFrame, VirtAddr};
+use crate::{gdt, memory, spinlock::SpinLock, time::{self, Instant}};
+
+mod context;
+mod stack;
+
+pub use stack::STACK_SIZE;
+pub(crate) use stack::Stack;
+
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+static SCHEDULER: SpinLock<Option<Scheduler>> = SpinLock::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    /// Waiting in the run queue.
+    Ready,
+    Sleeping(Instant),
+    /// Waiting for `unpark`.
+    Parked,
+    Exited,
+}
+
+struct Thread {
+    name: Cow<'static, str>,
+    state: ThreadState,
+    /// Stack pointer saved when the thread was switched out.
+    rsp: u64,
+    /// `None` for the main thread, which runs on the bootloader's stack.
+    _stack: Option<Stack>,
+    /// Set by `unpark` while the thread isn't parked, the next `park` returns
+    /// right away.
+    unpark_token: bool,
+    /// Kernel stack pointer saved when the thread entered user mode, zero in
+    /// kernel mode. Interrupts from user mode use the stack below it.
+    user_rsp: u64,
+    /// Level 4 table of the process the thread runs, the kernel's if `None`.
+    page_table: Option<PhysFrame>,
+}
+
+impl Thread {
+    fn new(name: Cow<'static, str>, state: ThreadState, rsp: u64, stack: Option<Stack>) -> Box<Thread> {
+        Box::new(Thread {
+            name,
+            state,
+            rsp,
+            _stack: stack,
+            unpark_token: false,
+            user_rsp: 0,
+            page_table: None,
+        })
+    }
+}
+
+/// What `reschedule` needs to switch from the current thread to the next.
+struct Switch {
+    old_rsp: *mut u64,
+    new_rsp: u64,
+    user_rsp: u64,
+    page_table: PhysFrame,
+}
+
+/// Round-robin scheduler, only used with interrupts disabled.
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    /// Kept large enough to queue every thread, so the timer interrupt never
+    /// allocates.
+    ready: VecDeque<ThreadId>,
+    current: ThreadId,
+    /// Runs when no other thread can, it's never queued.
+    idle: ThreadId,
+}
+
+impl Scheduler {
+    fn thread_mut(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("thread doesn't exist")
+    }
+
+    fn wake_sleepers(&mut self, now: Instant) {
+        let Self { threads, ready, .. } = self;
+        for (id, thread) in threads.iter_mut() {
+            if matches!(thread.state, ThreadState::Sleeping(deadline) if deadline <= now) {
+                thread.state = ThreadState::Ready;
+                ready.push_back(*id);
+            }
+        }
+    }
+
+    /// Picks the thread to run next, the current one has already been given
+    /// its new state. Returns `None` if the current thread keeps running.
+    fn switch_next(&mut self) -> Option<Switch> {
+        self.wake_sleepers(Instant::now());
+
+        let current = self.current;
+        let current_runnable = self.thread_mut(current).state == ThreadState::Running;
+        let next = match self.ready.pop_front() {
+            Some(next) => next,
+            None if current_runnable => return None,
+            None if current == self.idle => panic!("idle thread stopped running"),
+            None => self.idle,
+        };
+
+        if current_runnable {
+            self.thread_mut(current).state = ThreadState::Ready;
+            if current != self.idle {
+                self.ready.push_back(current);
+            }
+        }
+
+        let next_thread = self.thread_mut(next);
+        next_thread.state = ThreadState::Running;
+        let new_rsp = next_thread.rsp;
+        let user_rsp = next_thread.user_rsp;
+        let page_table = next_thread.page_table.unwrap_or_else(memory::kernel_page_table);
+        let old_rsp = &mut self.thread_mut(current).rsp as *mut u64;
+        self.current = next;
+        Some(Switch { old_rsp, new_rsp, user_rsp, page_table })
+    }
+
+    /// Frees the stacks of exited threads, the current one is still using its.
+    fn reap_exited(&mut self) {
+        let current = self.current;
+        self.threads.retain(|id, thread| *id == current || thread.state != ThreadState::Exited);
+    }
+
+    fn unpark(&mut self, id: ThreadId) {
+        let thread = match self.threads.get_mut(&id) {
+            Some(thread) => thread,
+            None => return
+        };
+        match thread.state {
+            ThreadState::Parked => {
+                thread.state = ThreadState::Ready;
+                self.ready.push_back(id);
+            },
+            ThreadState::Exited => {},
+            _ => thread.unpark_token = true
+        }
+    }
+}
+
+/// Creates a thread that calls `main` the first time it's switched to.
+fn new_thread(name: Cow<'static, str>, main: ThreadMain) -> Box<Thread> {
+    let stack = Stack::allocate().expect("failed to allocate a thread stack");
+    let arg = Box::into_raw(Box::new(main));
+    let rsp = unsafe { context::init_stack(stack.top(), arg as u64) };
+    Thread::new(name, ThreadState::Ready, rsp, Some(stack))
+}
+
+/// First function of every thread, called by `context::thread_trampoline`.
+extern "C" fn thread_entry(main: *mut ThreadMain) -> ! {
+    // switched to with interrupts disabled
+    interrupts::enable();
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    exit();
+}
+
+/// Gives the current thread a new state with `update` and switches to the
+/// next thread to run, returning once the current thread is switched back to.
+///
+/// Does nothing before `init`.
+fn reschedule(update: impl FnOnce(&mut Thread)) {
+    interrupts::without_interrupts(|| {
+        let switch = {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = match scheduler.as_mut() {
+                Some(scheduler) => scheduler,
+                None => return
+            };
+            let current = scheduler.current;
+            update(scheduler.thread_mut(current));
+            scheduler.switch_next()
+        };
+
+        // the lock must be released, the next thread may not give it back
+        if let Some(switch) = switch {
+            if switch.user_rsp != 0 {
+                gdt::set_kernel_stack(VirtAddr::new(switch.user_rsp));
+            }
+            unsafe {
+                memory::switch_page_table(switch.page_table);
+                context::switch_context(switch.old_rsp, switch.new_rsp);
+            }
+        }
+    });
+}
+
+/// Turns the running code into the "main" thread and starts the idle thread.
+///
+/// Must be called once the heap and `memory::init_mapper` are set up.
+pub fn init() {
+    let main_id = ThreadId::new();
+    let main = Thread::new(Cow::Borrowed("main"), ThreadState::Running, 0, None);
+
+    let idle_id = ThreadId::new();
+    let idle = new_thread(Cow::Borrowed("idle"), Box::new(|| loop {
+        x86_64::instructions::hlt();
+    }));
+
+    let mut threads = BTreeMap::new();
+    threads.insert(main_id, main);
+    threads.insert(idle_id, idle);
+    let scheduler = Scheduler {
+        threads,
+        ready: VecDeque::with_capacity(2),
+        current: main_id,
+        idle: idle_id,
+    };
+    *SCHEDULER.lock() = Some(scheduler);
+}
+
+/// Called by the timer interrupt handler after the end of interrupt
+///
+/// Moves on to the next ready thread, the interrupted one continues when its
+/// turn comes again.
+///
+/// Must not block or allocate.
+pub(crate) fn preempt() {
+    let busy = {
+        let mut scheduler = SCHEDULER.lock();
+        match scheduler.as_mut() {
+            Some(scheduler) => {
+                scheduler.wake_sleepers(Instant::now());
+                !scheduler.ready.is_empty()
+            },
+            None => false
+        }
+    };
+    if busy {
+        // an idle executor may have stopped the periodic tick, which the
+        // threads need to take turns
+        time::resume_periodic_tick();
+        reschedule(|_| {});
+    }
+}
+
+/// Spawns a thread running `f`, returning a handle to join it.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_named("unnamed", f)
+}
+
+/// Spawns a thread running `f` under `name`.
+pub fn spawn_named<F, T>(name: impl Into<Cow<'static, str>>, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        result: SpinLock::new(None),
+        joiner: SpinLock::new(None),
+    });
+    let thread_packet = packet.clone();
+    let thread = new_thread(name.into(), Box::new(move || {
+        thread_packet.complete(f());
+    }));
+
+    let id = ThreadId::new();
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut().expect("threads not initialized");
+    scheduler.reap_exited();
+    scheduler.threads.insert(id, thread);
+    let len = scheduler.threads.len();
+    scheduler.ready.reserve(len);
+    scheduler.ready.push_back(id);
+
+    JoinHandle { id, packet }
+}
+
+pub fn current_id() -> Option<ThreadId> {
+    SCHEDULER.lock().as_ref().map(|scheduler| scheduler.current)
+}
+
+/// Lets the other ready threads run before continuing.
+pub fn yield_now() {
+    reschedule(|_| {});
+}
+
+/// Blocks the current thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    if current_id().is_none() {
+        while Instant::now() < deadline {
+            x86_64::instructions::hlt();
+        }
+        return;
+    }
+    reschedule(|thread| thread.state = ThreadState::Sleeping(deadline));
+}
+
+/// Blocks the current thread until `unpark` is called for it, returns right
+/// away if it already was since the last `park`.
+pub fn park() {
+    reschedule(|thread| {
+        if !core::mem::take(&mut thread.unpark_token) {
+            thread.state = ThreadState::Parked;
+        }
+    });
+}
+
+pub fn unpark(id: ThreadId) {
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.unpark(id);
+    }
+}
+
+/// Ends the current thread, its stack is freed by the next `spawn`.
+pub fn exit() -> ! {
+    reschedule(|thread| thread.state = ThreadState::Exited);
+    unreachable!("exited thread was switched back to");
+}
+
+/// Whether threads other than the current one are waiting for the CPU.
+pub fn has_ready_threads() -> bool {
+    SCHEDULER.lock().as_mut().map_or(false, |scheduler| {
+        scheduler.wake_sleepers(Instant::now());
+        !scheduler.ready.is_empty()
+    })
+}
+
+/// Earliest deadline of the sleeping threads.
+pub fn next_wakeup() -> Option<Instant> {
+    SCHEDULER.lock().as_ref().and_then(|scheduler| {
+        scheduler.threads.values()
+            .filter_map(|thread| match thread.state {
+                ThreadState::Sleeping(deadline) => Some(deadline),
+                _ => None
+            })
+            .min()
+    })
+}
+
+/// Runs the current thread on the level 4 table at `page_table`, or the
+/// kernel's if `None`.
+///
+/// This function is unsafe because the caller must guarantee that the table
+/// maps the kernel like the kernel's.
+pub(crate) unsafe fn set_page_table(page_table: Option<PhysFrame>) {
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut().expect("threads not initialized");
+    let current = scheduler.current;
+    scheduler.thread_mut(current).page_table = page_table;
+    memory::switch_page_table(page_table.unwrap_or_else(memory::kernel_page_table));
+}
+
+/// Where user mode entry saves the kernel stack pointer of the current
+/// thread, valid for as long as the thread.
+pub(crate) fn user_rsp_slot() -> *mut u64 {
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut().expect("threads not initialized");
+    let current = scheduler.current;
+    &mut scheduler.thread_mut(current).user_rsp as *mut u64
+}
+
+/// Kernel stack pointer saved when the current thread entered user mode,
+/// `None` while it's not in user mode.
+pub(crate) fn user_rsp() -> Option<u64> {
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut()?;
+    let current = scheduler.current;
+    match scheduler.thread_mut(current).user_rsp {
+        0 => None,
+        rsp => Some(rsp)
+    }
+}
+
+/// Point in time view of a thread, see `thread_snapshot`.
+#[derive(Debug, Clone)]
+pub struct ThreadSnapshot {
+    pub id: ThreadId,
+    pub name: Cow<'static, str>,
+    pub state: ThreadState,
+}
+
+/// Lists the threads, including exited ones that haven't been freed yet.
+pub fn thread_snapshot() -> Vec<ThreadSnapshot> {
+    SCHEDULER.lock().as_ref().map_or_else(Vec::new, |scheduler| {
+        scheduler.threads.iter()
+            .map(|(id, thread)| ThreadSnapshot { id: *id, name: thread.name.clone(), state: thread.state })
+            .collect()
+    })
+}
+
+/// Whether `address` is in the guard page of a thread stack, which means the
+/// thread overflowed it.
+pub fn is_stack_guard_page(address: VirtAddr) -> bool {
+    stack::is_guard_page(address)
+}
+
+struct Packet<T> {
+    result: SpinLock<Option<T>>,
+    /// Thread blocked in `JoinHandle::join`.
+    joiner: SpinLock<Option<ThreadId>>,
+}
+
+impl<T> Packet<T> {
+    fn complete(&self, result: T) {
+        *self.result.lock() = Some(result);
+        let joiner = *self.joiner.lock();
+        if let Some(joiner) = joiner {
+            unpark(joiner);
+        }
+    }
+
+    fn take_result(&self) -> Option<T> {
+        self.result.lock().take()
+    }
+}
+
+/// Owned permission to join a thread.
+///
+/// Dropping the handle detaches the thread.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread_id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.result.lock().is_some()
+    }
+
+    /// Blocks until the thread has returned, giving its result.
+    pub fn join(self) -> T {
+        let current = current_id().expect("threads not initialized");
+        *self.packet.joiner.lock() = Some(current);
+        loop {
+            // checked after registering, so the wake up can't be missed
+            if let Some(result) = self.packet.take_result() {
+                return result;
+            }
+            park();
+        }
+    }
+}
+
+impl<T> core::fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("JoinHandle").field("thread_id", &self.id).finish()
+    }
+}
@@ -0,0 +1,90 @@
+use alloc::vec::Vec;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr
+};
+use crate::{memory, spinlock::SpinLock};
+
+/// Start of the virtual region reserved for thread stacks.
+const STACKS_START: u64 = 0x_5555_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+pub const STACK_SIZE: u64 = 8 * PAGE_SIZE;
+/// Each slot is an unmapped guard page followed by the stack, so an overflow
+/// faults instead of corrupting the stack below.
+const SLOT_SIZE: u64 = PAGE_SIZE + STACK_SIZE;
+const MAX_STACKS: u64 = 4096;
+
+struct StackSlots {
+    /// Slots past this one have never been mapped.
+    next: u64,
+    /// Slots of exited threads, still mapped.
+    free: Vec<u64>,
+}
+
+static SLOTS: SpinLock<StackSlots> = SpinLock::new(StackSlots { next: 0, free: Vec::new() });
+
+/// Stack of a thread, returned for reuse when dropped.
+pub struct Stack {
+    slot: u64,
+}
+
+impl Stack {
+    /// Reuses the stack of an exited thread or maps a new one, returns `None`
+    /// if the region is exhausted or no frames are left.
+    pub fn allocate() -> Option<Stack> {
+        let (slot, recycled) = {
+            let mut slots = SLOTS.lock();
+            match slots.free.pop() {
+                Some(slot) => (slot, true),
+                None if slots.next == MAX_STACKS => return None,
+                None => {
+                    slots.next += 1;
+                    (slots.next - 1, false)
+                }
+            }
+        };
+
+        let stack = Stack { slot };
+        if !recycled {
+            let pages = Page::<Size4KiB>::range(
+                Page::containing_address(stack.bottom()),
+                Page::containing_address(stack.top())
+            );
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            if let Err(err) = memory::map_pages(pages, flags) {
+                log::error!("mapping thread stack failed: {:?}", err);
+                // the slot may be partially mapped, don't hand it out again
+                core::mem::forget(stack);
+                return None;
+            }
+        }
+        Some(stack)
+    }
+
+    fn guard_page(&self) -> VirtAddr {
+        VirtAddr::new(STACKS_START + self.slot * SLOT_SIZE)
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard_page() + PAGE_SIZE
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + STACK_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        SLOTS.lock().free.push(self.slot);
+    }
+}
+
+/// Whether `address` is in the guard page below a thread stack.
+pub fn is_guard_page(address: VirtAddr) -> bool {
+    let offset = match address.as_u64().checked_sub(STACKS_START) {
+        Some(offset) => offset,
+        None => return false
+    };
+    offset < MAX_STACKS * SLOT_SIZE && offset % SLOT_SIZE < PAGE_SIZE
+}
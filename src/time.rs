@@ -1,25 +1,324 @@
-use spin::Mutex;
-use core::time::Duration;
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    ops::{Add, Sub},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration
+};
+use x86_64::instructions::port::Port;
+use crate::{pit::{self, PIT, PIT_FREQUENCY}, spinlock::SpinLock};
 
-static SYSTEM_CLOCK: Mutex<Duration> = Mutex::new(Duration::from_secs(0));
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+/// Interval between interrupts of the PIT at its default divisor.
+pub const PIT_TICK_RATE: Duration = Duration::from_nanos(54_925_400);
+#[cfg(feature="acpi-feat")]
+pub const HPET_TICK_RATE: Duration = Duration::from_millis(10);
 
-pub (crate) fn system_clock_tick(rate: Duration) {
-    let mut clock = SYSTEM_CLOCK.lock();
-    *clock += rate;
+/// Uptime advanced by the timer interrupt, used until the TSC is calibrated.
+static TICK_CLOCK_NANOS: AtomicU64 = AtomicU64::new(0);
+/// Interval between timer interrupts of the current tick source.
+static TICK_RATE_NANOS: AtomicU64 = AtomicU64::new(PIT_TICK_RATE.as_nanos() as u64);
+static TICK_SOURCE: SpinLock<TickSource> = SpinLock::new(TickSource::Pit);
+/// Set while the tick source is armed as a one-shot timer instead of ticking.
+static ONE_SHOT_MODE: AtomicBool = AtomicBool::new(false);
+/// TSC ticks per second, zero while uncalibrated.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// TSC value corresponding to `Instant(0)`.
+static TSC_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+
+pub (crate) fn system_clock_tick() {
+    if ONE_SHOT_MODE.load(Ordering::Relaxed) {
+        // not a periodic tick, the TSC keeps the uptime anyway
+        return;
+    }
+    TICK_CLOCK_NANOS.fetch_add(TICK_RATE_NANOS.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Hardware timer driving the timer interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    Pit,
+    #[cfg(feature="acpi-feat")]
+    Hpet,
+}
+
+/// Makes `source` the timer interrupt source, returns false if it's unavailable.
+pub fn set_tick_source(source: TickSource) -> bool {
+    let rate = match source {
+        TickSource::Pit => {
+            #[cfg(feature="acpi-feat")]
+            if let Some(hpet) = crate::hpet::HPET.get() {
+                hpet.stop_timer(0);
+                hpet.disable_legacy_replacement();
+            }
+            PIT_TICK_RATE
+        },
+        #[cfg(feature="acpi-feat")]
+        TickSource::Hpet => {
+            if !crate::hpet::start_tick(HPET_TICK_RATE) {
+                return false;
+            }
+            HPET_TICK_RATE
+        }
+    };
+    TICK_RATE_NANOS.store(rate.as_nanos() as u64, Ordering::Relaxed);
+    *TICK_SOURCE.lock() = source;
+    log::info!("tick source: {:?}, rate={:?}", source, rate);
+    true
+}
+
+pub fn tick_rate() -> Duration {
+    Duration::from_nanos(TICK_RATE_NANOS.load(Ordering::Relaxed))
+}
+
+/// Arms the tick source to interrupt once at `deadline`, or as late as it can
+/// when there is none, so an idle CPU isn't woken by periodic ticks.
+///
+/// The PIT can't wait longer than ~55ms, so a later deadline just takes more
+/// than one wake up. Returns false while the TSC isn't calibrated, as the
+/// uptime is still counted in periodic ticks then, and if the HPET couldn't
+/// be armed, in which case it keeps ticking periodically. Must be called with
+/// interrupts disabled and followed by `resume_periodic_tick`.
+pub(crate) fn program_one_shot(deadline: Option<Instant>) -> bool {
+    if tsc_frequency().is_none() {
+        return false;
+    }
+
+    let duration = deadline.map(|deadline| deadline.duration_since(Instant::now()));
+    ONE_SHOT_MODE.store(true, Ordering::Relaxed);
+    match *TICK_SOURCE.lock() {
+        TickSource::Pit => {
+            let count = duration.map(pit::duration_to_count).unwrap_or(pit::MAX_COUNT);
+            PIT.lock().set_one_shot(count);
+        },
+        #[cfg(feature="acpi-feat")]
+        TickSource::Hpet => {
+            let hpet = crate::hpet::HPET.get().expect("HPET tick source without HPET");
+            match duration {
+                Some(duration) => if !hpet.start_timer(0, crate::hpet::TimerMode::OneShot, duration) {
+                    // the deadline couldn't be armed, keep ticking instead
+                    ONE_SHOT_MODE.store(false, Ordering::Relaxed);
+                    crate::hpet::start_tick(HPET_TICK_RATE);
+                    return false;
+                },
+                None => hpet.stop_timer(0)
+            }
+        }
+    }
+    true
+}
+
+/// Puts the tick source back in periodic mode after `program_one_shot`.
+pub(crate) fn resume_periodic_tick() {
+    if !ONE_SHOT_MODE.swap(false, Ordering::Relaxed) {
+        return;
+    }
+
+    match *TICK_SOURCE.lock() {
+        TickSource::Pit => PIT.lock().set_periodic(pit::MAX_COUNT),
+        #[cfg(feature="acpi-feat")]
+        TickSource::Hpet => {
+            crate::hpet::start_tick(HPET_TICK_RATE);
+        }
+    }
 }
 
 pub fn get_system_uptime() -> Duration {
-    *SYSTEM_CLOCK.lock()
-}
-
-// #[test_case]
-// fn test_system_clock() {
-//     let t0 = get_system_uptime();
-//     log::trace!("t0 = {:?}", t0);
-//     let mut t = get_system_uptime();
-//     log::trace!("t = {:?}", t);
-//     while t == t0 {
-//         t = get_system_uptime();
-//         // log::debug!("t = {:?}", t);
-//     }
-// }
+    Instant::now().duration_since(Instant::BOOT)
+}
+
+/// A monotonic point in time with nanosecond resolution, measured since boot.
+///
+/// Backed by the TSC once it has been calibrated by [`init`], reading it
+/// doesn't take any lock so it can be used from interrupt handlers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const BOOT: Instant = Instant(0);
+
+    pub fn now() -> Instant {
+        let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+        if frequency == 0 {
+            return Instant(TICK_CLOCK_NANOS.load(Ordering::Relaxed));
+        }
+
+        let ticks = read_tsc().saturating_sub(TSC_AT_BOOT.load(Ordering::Relaxed));
+        Instant((ticks as u128 * NANOS_PER_SEC / frequency as u128) as u64)
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos()).ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(Instant)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Wall clock time, measured as the duration since the unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(Duration);
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::from_secs(0));
+
+    pub fn now() -> SystemTime {
+        SystemTime(crate::rtc::unix_time())
+    }
+
+    /// Returns `None` if `earlier` is later than `self`.
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    pub fn elapsed(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(*self)
+    }
+
+    pub fn date_time(&self) -> crate::rtc::DateTime {
+        crate::rtc::DateTime::from_unix_timestamp(self.0.as_secs())
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, duration: Duration) -> SystemTime {
+        SystemTime(self.0 + duration)
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, duration: Duration) -> SystemTime {
+        SystemTime(self.0 - duration)
+    }
+}
+
+/// Calibrates the TSC and switches `Instant` over to it.
+///
+/// Must be called with interrupts disabled, the calibration busy-waits on the
+/// HPET main counter when ACPI found one and on PIT channel 2 otherwise.
+pub fn init() {
+    if !tsc_is_invariant() {
+        log::warn!("TSC is not invariant, time may drift with frequency scaling");
+    }
+
+    let frequency = calibrate_with_hpet().unwrap_or_else(calibrate_with_pit);
+    log::info!("TSC frequency: {} kHz", frequency / 1000);
+
+    // keep the clock continuous with the ticks counted so far
+    let uptime_ticks = TICK_CLOCK_NANOS.load(Ordering::Relaxed) as u128 * frequency as u128 / NANOS_PER_SEC;
+    TSC_AT_BOOT.store(read_tsc().saturating_sub(uptime_ticks as u64), Ordering::Relaxed);
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+
+    #[cfg(feature="hpet-tick")]
+    if !set_tick_source(TickSource::Hpet) {
+        log::warn!("HPET can't generate the timer interrupt, keeping the PIT");
+    }
+}
+
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency)
+    }
+}
+
+fn read_tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn tsc_is_invariant() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+//https://wiki.osdev.org/APIC_timer#Initial_Count_calibration
+fn calibrate_with_pit() -> u64 {
+    let mut pit_command_port = Port::<u8>::new(0x43);
+    let mut pit_channel2_port = Port::<u8>::new(0x42);
+    let mut speaker_port = Port::<u8>::new(0x61);
+
+    let count = PIT_FREQUENCY * CALIBRATION_TIME.as_millis() as u64 / 1000;
+
+    unsafe {
+        // enable channel 2 gate, keep the speaker disconnected
+        let value = speaker_port.read() & 0xFC;
+        speaker_port.write(value | 0x01);
+
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        pit_command_port.write(0b1011_0000);
+        pit_channel2_port.write(count as u8);
+        pit_channel2_port.write((count >> 8) as u8);
+
+        // restart the count by toggling the gate
+        speaker_port.write(value);
+        speaker_port.write(value | 0x01);
+
+        let start = read_tsc();
+        // output of channel 2 goes high when the count reaches zero
+        while speaker_port.read() & 0x20 == 0 {}
+        let end = read_tsc();
+
+        speaker_port.write(value);
+
+        (end - start) * 1000 / CALIBRATION_TIME.as_millis() as u64
+    }
+}
+
+#[cfg(feature="acpi-feat")]
+fn calibrate_with_hpet() -> Option<u64> {
+    let hpet = crate::hpet::HPET.get()?;
+    let counter_ticks = hpet.duration_to_ticks(CALIBRATION_TIME);
+
+    let counter_start = hpet.counter();
+    let start = read_tsc();
+    while hpet.counter().wrapping_sub(counter_start) < counter_ticks {}
+    let end = read_tsc();
+
+    Some((end - start) * 1000 / CALIBRATION_TIME.as_millis() as u64)
+}
+
+#[cfg(not(feature="acpi-feat"))]
+fn calibrate_with_hpet() -> Option<u64> {
+    None
+}
+
+#[test_case]
+fn test_instant_is_monotonic() {
+    let t0 = Instant::now();
+    let mut t = Instant::now();
+    while t == t0 {
+        t = Instant::now();
+    }
+    assert!(t > t0);
+}
@@ -1,13 +1,9 @@
 use volatile::Volatile;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
-use x86_64::instructions::{
-    interrupts,
-    port::Port
-};
-use alloc::str::FromStr;
-use crate::error::Error;
+use x86_64::instructions::port::Port;
+use alloc::{collections::VecDeque, str::FromStr, string::String, vec::Vec};
+use crate::{encoding, error::Error, spinlock::SpinLock};
 
 #[cfg(feature="random")]
 use rand::{
@@ -122,6 +118,11 @@ impl ColorCode {
     fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    /// Swaps the foreground and background colors.
+    fn inverted(self) -> ColorCode {
+        ColorCode(self.0.rotate_left(4))
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -134,11 +135,46 @@ struct ScreenChar {
 pub const BUFFER_HEIGHT: usize = 25;
 pub const BUFFER_WIDTH: usize = 80;
 
+/// Rows kept once they scroll off the top, changed with `set_scrollback_lines`.
+pub const DEFAULT_SCROLLBACK_LINES: usize = 500;
+/// Each row takes 160 bytes of the heap.
+pub const MAX_SCROLLBACK_LINES: usize = 2000;
+
+type Row = [ScreenChar; BUFFER_WIDTH];
+
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// Drawn over the text by inverting the colors of its cells, which
+/// `Writer::read_cell` and `Writer::write_cell` take into account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Overlay {
+    pointer: Option<(usize,usize)>,//x,y
+    /// Cells from the first to the second in reading order, either way round.
+    selection: Option<((usize,usize),(usize,usize))>,
+}
+
+impl Overlay {
+    /// First and last selected cell as offsets into the buffer.
+    fn selection_range(&self) -> Option<(usize, usize)> {
+        self.selection.map(|((from_x, from_y), (to_x, to_y))| {
+            let from = from_y * BUFFER_WIDTH + from_x;
+            let to = to_y * BUFFER_WIDTH + to_x;
+            (from.min(to), from.max(to))
+        })
+    }
+
+    /// The pointer inverts a selected cell back, so it stays visible on the
+    /// selection.
+    fn is_inverted(&self, x: usize, y: usize) -> bool {
+        let offset = y * BUFFER_WIDTH + x;
+        let selected = self.selection_range().map_or(false, |(start, end)| (start..=end).contains(&offset));
+        selected != (self.pointer == Some((x, y)))
+    }
+}
+
 struct Cursor {
     position: (usize,usize),//x,y
     cursor_high: Port<u8>,
@@ -160,14 +196,23 @@ impl Cursor {
 
     fn set_position_raw(&mut self) {
         let (x, y) = self.position;
-        let pos = y * BUFFER_WIDTH + x;
+        self.write_position(y * BUFFER_WIDTH + x);
+    } 
+
+    /// Moves the cursor past the end of the screen, where it isn't drawn,
+    /// until the next `set_position_raw`.
+    fn hide(&mut self) {
+        self.write_position(BUFFER_HEIGHT * BUFFER_WIDTH);
+    }
+
+    fn write_position(&mut self, pos: usize) {
         unsafe {
             self.cursor_high.write(0x0F);
             self.cursor_low.write((pos & 0xFF) as u8);
             self.cursor_high.write(0x0E);
             self.cursor_low.write(((pos >> 8) & 0xFF) as u8);
         }
-    } 
+    }
 }
 
 pub struct Writer {
@@ -175,11 +220,26 @@ pub struct Writer {
     foreground: Color,
     background: Color,
     buffer: &'static mut Buffer,
-    cursor: Cursor
+    cursor: Cursor,
+    overlay: Overlay,
+    /// Rows scrolled off the top, oldest first.
+    history: VecDeque<Row>,
+    scrollback_lines: usize,
+    /// Rows of history the view is scrolled back by, 0 for the live screen.
+    view_offset: usize,
+    /// Live screen, kept aside while the view shows the history.
+    live: Option<Vec<Row>>
 }
 
+/// Held by animations drawing across several frames, so they don't interleave.
+///
+/// `WRITER` is a spin lock used by interrupt handlers as well, so it must
+/// never be held across an `.await`.
+#[cfg(feature="random")]
+static SCREEN_ANIMATION: crate::task::sync::Mutex<()> = crate::task::sync::Mutex::new(());
+
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = {
+    pub static ref WRITER: SpinLock<Writer> = {
         let mut writer = Writer {
             position: (0,0),
             foreground: Color::LightGray,
@@ -189,11 +249,17 @@ lazy_static! {
                 position: (0,0),
                 cursor_high: Port::new(0x3D4),
                 cursor_low: Port::new(0x3D5)
-            }
+            },
+            overlay: Overlay { pointer: None, selection: None },
+            // reserved by `init` once there is a heap
+            history: VecDeque::new(),
+            scrollback_lines: DEFAULT_SCROLLBACK_LINES,
+            view_offset: 0,
+            live: None
         };
         writer.cursor.enable(0, 15);
         writer.cursor.set_position_raw();
-        Mutex::new(writer)
+        SpinLock::new(writer)
     };
 }
 
@@ -209,7 +275,7 @@ impl Writer {
                 }
 
                 let color_code = ColorCode::new(self.foreground, self.background);
-                self.buffer.chars[self.position.1][self.position.0].write(ScreenChar {
+                self.write_cell(self.position.0, self.position.1, ScreenChar {
                     cp437_character: byte,
                     color_code: color_code,
                 });
@@ -219,12 +285,24 @@ impl Writer {
     }
 
     fn new_line(&mut self) {
+        self.show_live();
         self.position.0 = 0;
         if self.position.1 == BUFFER_HEIGHT - 1 {
+            // the selected text moves away from under the selection
+            self.clear_selection();
+            // printing never allocates, the history only fills the room
+            // reserved by `set_scrollback_lines`
+            if self.history.len() >= self.scrollback_lines.min(self.history.capacity()) {
+                self.history.pop_front();
+            }
+            if self.history.len() < self.history.capacity() {
+                let row = self.read_row(0);
+                self.history.push_back(row);
+            }
             for row in 1..BUFFER_HEIGHT {
                 for col in 0..BUFFER_WIDTH {
-                    let character = self.buffer.chars[row][col].read();
-                    self.buffer.chars[row - 1][col].write(character);
+                    let character = self.read_cell(col, row);
+                    self.write_cell(col, row - 1, character);
                 }
             }
             
@@ -240,8 +318,147 @@ impl Writer {
             color_code: ColorCode::new(Color::LightGray, Color::Black),
         };
         for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+            self.write_cell(col, row, blank);
+        }
+    }
+
+    /// Character at the given cell, as written before the overlay inverted it.
+    fn read_cell(&self, x: usize, y: usize) -> ScreenChar {
+        let mut screen_char = self.buffer.chars[y][x].read();
+        if self.overlay.is_inverted(x, y) {
+            screen_char.color_code = screen_char.color_code.inverted();
+        }
+        screen_char
+    }
+
+    fn read_row(&self, y: usize) -> Row {
+        core::array::from_fn(|x| self.read_cell(x, y))
+    }
+
+    /// Writes to the live screen, going back to it if the history is shown.
+    fn write_cell(&mut self, x: usize, y: usize, screen_char: ScreenChar) {
+        self.show_live();
+        self.draw_cell(x, y, screen_char);
+    }
+
+    /// Writes to the screen as shown.
+    fn draw_cell(&mut self, x: usize, y: usize, mut screen_char: ScreenChar) {
+        if self.overlay.is_inverted(x, y) {
+            screen_char.color_code = screen_char.color_code.inverted();
         }
+        self.buffer.chars[y][x].write(screen_char);
+    }
+
+    /// Redraws the cells whose inversion changes with the new overlay.
+    fn set_overlay(&mut self, overlay: Overlay) {
+        let old = core::mem::replace(&mut self.overlay, overlay);
+        for y in 0..BUFFER_HEIGHT {
+            for x in 0..BUFFER_WIDTH {
+                if old.is_inverted(x, y) != overlay.is_inverted(x, y) {
+                    let mut screen_char = self.buffer.chars[y][x].read();
+                    screen_char.color_code = screen_char.color_code.inverted();
+                    self.buffer.chars[y][x].write(screen_char);
+                }
+            }
+        }
+    }
+
+    /// Scrolls the view `lines` back into the history, or forward towards the
+    /// live screen for negative `lines`.
+    pub fn scroll_view(&mut self, lines: isize) {
+        let offset = (self.view_offset as isize + lines).clamp(0, self.history.len() as isize);
+        self.set_view_offset(offset as usize);
+    }
+
+    /// Goes back to the live screen if the history is shown.
+    pub fn show_live(&mut self) {
+        self.set_view_offset(0);
+    }
+
+    fn set_view_offset(&mut self, offset: usize) {
+        if offset == self.view_offset {
+            return;
+        }
+        // the selection is of the text shown
+        self.clear_selection();
+        let live = match self.live.take() {
+            Some(live) => live,
+            None => (0..BUFFER_HEIGHT).map(|y| self.read_row(y)).collect()
+        };
+        self.view_offset = offset;
+        let top = self.history.len() - offset;
+        for y in 0..BUFFER_HEIGHT {
+            let row = match self.history.get(top + y) {
+                Some(row) => *row,
+                None => live[top + y - self.history.len()]
+            };
+            for (x, screen_char) in row.into_iter().enumerate() {
+                self.draw_cell(x, y, screen_char);
+            }
+        }
+        if offset == 0 {
+            self.cursor.set_position_raw();
+        } else {
+            self.cursor.hide();
+            self.live = Some(live);
+        }
+    }
+
+    pub fn scrollback_lines(&self) -> usize {
+        self.scrollback_lines
+    }
+
+    /// Changes how many rows the history keeps, dropping the oldest ones
+    /// beyond that, and reserves the memory for them.
+    pub fn set_scrollback_lines(&mut self, lines: usize) -> Result<(), Error> {
+        if lines > MAX_SCROLLBACK_LINES {
+            return Err(Error::ScrollbackTooLong);
+        }
+        self.show_live();
+        let excess = self.history.len().saturating_sub(lines);
+        self.history.drain(..excess);
+        self.history.shrink_to(lines);
+        // without the memory, the history keeps the rows it has room for
+        self.scrollback_lines = lines;
+        self.history.try_reserve_exact(lines - self.history.len())
+            .map_err(|_| Error::OutOfMemory)
+    }
+
+    /// Moves the mouse pointer, which is independent of the text cursor.
+    pub fn set_pointer(&mut self, x: usize, y: usize) {
+        let pointer = Some((x.min(BUFFER_WIDTH - 1), y.min(BUFFER_HEIGHT - 1)));
+        self.set_overlay(Overlay { pointer, ..self.overlay });
+    }
+
+    /// Highlights the cells from `from` to `to`, both included.
+    pub fn select(&mut self, from: (usize,usize), to: (usize,usize)) {
+        let clamp = |(x, y): (usize,usize)| (x.min(BUFFER_WIDTH - 1), y.min(BUFFER_HEIGHT - 1));
+        self.set_overlay(Overlay { selection: Some((clamp(from), clamp(to))), ..self.overlay });
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.set_overlay(Overlay { selection: None, ..self.overlay });
+    }
+
+    /// Text of the selection, with a line break between rows and without
+    /// the blanks at their ends.
+    pub fn selected_text(&self) -> String {
+        let mut text = String::new();
+        if let Some((start, end)) = self.overlay.selection_range() {
+            for offset in start..=end {
+                let (x, y) = (offset % BUFFER_WIDTH, offset / BUFFER_WIDTH);
+                if x == 0 && offset != start {
+                    text.truncate(text.trim_end_matches(' ').len());
+                    text.push('\n');
+                }
+                match encoding::cp437_to_char(self.read_cell(x, y).cp437_character) {
+                    '\0' => text.push(' '),
+                    character => text.push(character)
+                }
+            }
+            text.truncate(text.trim_end().len());
+        }
+        text
     }
 
     pub fn write_string(&mut self, s: &str) {
@@ -250,11 +467,13 @@ impl Writer {
     }
 
     pub fn set_cursor_position(&mut self, x: usize, y: usize) {
+        self.show_live();
         self.cursor.position = (x,y);
         self.cursor.set_position_raw();
     }
 
     pub fn cursor_position_delta(&mut self, delta_x: i16, delta_y: i16) {
+        self.show_live();
         self.cursor.position.0 = (self.cursor.position.0 as i16 + delta_x).clamp(0, BUFFER_WIDTH as i16 - 1) as usize;
         self.cursor.position.1 = (self.cursor.position.1 as i16 - delta_y).clamp(0, BUFFER_HEIGHT as i16 - 1) as usize;
 
@@ -267,7 +486,7 @@ impl Writer {
             self.position.0 -= 1;
 
             let color_code = ColorCode::new(Color::LightGray, Color::Black);
-            self.buffer.chars[self.position.1][self.position.0].write(ScreenChar {
+            self.write_cell(self.position.0, self.position.1, ScreenChar {
                 cp437_character: b'\0',
                 color_code: color_code,
             });
@@ -292,16 +511,6 @@ impl Writer {
         self.foreground
     }
 
-    #[cfg(feature="random")]
-    pub async fn randomize_vga_buffer(&mut self) {
-        let mut rng = SmallRng::seed_from_u64(0);
-
-        for row in 0..BUFFER_HEIGHT {
-            self.random_line(row, &mut rng);
-            crate::task::sleep::Sleep::new(Duration::from_nanos(1)).await
-        }
-    }
-
     #[cfg(feature="random")]
     fn random_line<R: Rng>(&mut self, row: usize, rng: &mut R) {
         for col in 0..BUFFER_WIDTH {
@@ -313,11 +522,12 @@ impl Writer {
                 cp437_character: cp437_character as u8,
                 color_code: ColorCode::new(foreground, background),
             };
-            self.buffer.chars[row][col].write(char);
+            self.write_cell(col, row, char);
         }
     }
 
     pub fn clear(&mut self) {
+        self.clear_selection();
         for row in 0..BUFFER_HEIGHT {
             self.clear_row(row);
         }
@@ -327,7 +537,7 @@ impl Writer {
 
     fn write_screen_char_at_position(&mut self, screen_char: ScreenChar, x: usize, y: usize) {
         if x < BUFFER_WIDTH && y < BUFFER_HEIGHT {
-            self.buffer.chars[y][x].write(screen_char);
+            self.write_cell(x, y, screen_char);
         }
     }
 
@@ -405,55 +615,83 @@ impl fmt::Write for Writer {
     }
 }
 
+/// Reserves the default scrollback history, must be called after the heap
+/// is initialized.
+pub fn init() {
+    if set_scrollback_lines(DEFAULT_SCROLLBACK_LINES).is_err() {
+        log::warn!("no memory for the scrollback history");
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 #[doc(hidden)]
 pub fn _print_error(args: fmt::Arguments) {
     use core::fmt::Write;
 
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        let curr_background = writer.get_background_color();
-        let curr_foreground = writer.get_foreground_color();
-        writer.set_background_color(Color::Black);
-        writer.set_foreground_color(Color::Red);
-        writer.write_fmt(args).unwrap();
-        writer.set_background_color(curr_background);
-        writer.set_foreground_color(curr_foreground);
-    });
+    let mut writer = WRITER.lock();
+    let curr_background = writer.get_background_color();
+    let curr_foreground = writer.get_foreground_color();
+    writer.set_background_color(Color::Black);
+    writer.set_foreground_color(Color::Red);
+    writer.write_fmt(args).unwrap();
+    writer.set_background_color(curr_background);
+    writer.set_foreground_color(curr_foreground);
 }
 
 pub fn erase_character() {
-    interrupts::without_interrupts(|| {
-        WRITER.lock().erase_character();
-    });
+    WRITER.lock().erase_character();
 }
 
 pub fn set_cursor_position(x: usize, y: usize) {
-    interrupts::without_interrupts(|| {
-        WRITER.lock().set_cursor_position(x,y);
-    });
+    WRITER.lock().set_cursor_position(x,y);
 }
 
 pub fn cursor_position_delta(delta_x: i16, delta_y: i16) {
-    interrupts::without_interrupts(|| {
-        WRITER.lock().cursor_position_delta(delta_x, delta_y);
-    });
+    WRITER.lock().cursor_position_delta(delta_x, delta_y);
+}
+
+pub fn scroll_view(lines: isize) {
+    WRITER.lock().scroll_view(lines);
+}
+
+pub fn show_live() {
+    WRITER.lock().show_live();
+}
+
+pub fn scrollback_lines() -> usize {
+    WRITER.lock().scrollback_lines()
+}
+
+pub fn set_scrollback_lines(lines: usize) -> Result<(), Error> {
+    WRITER.lock().set_scrollback_lines(lines)
+}
+
+pub fn set_pointer(x: usize, y: usize) {
+    WRITER.lock().set_pointer(x, y);
+}
+
+pub fn select(from: (usize,usize), to: (usize,usize)) {
+    WRITER.lock().select(from, to);
+}
+
+pub fn clear_selection() {
+    WRITER.lock().clear_selection();
+}
+
+pub fn selected_text() -> String {
+    WRITER.lock().selected_text()
 }
 
 pub fn set_color(foreground: Color, background: Color) {
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        writer.set_foreground_color(foreground);
-        writer.set_background_color(background);
-    });
+    let mut writer = WRITER.lock();
+    writer.set_foreground_color(foreground);
+    writer.set_background_color(background);
 }
 
 pub fn clear() {
@@ -462,22 +700,24 @@ pub fn clear() {
 
 #[cfg(feature="random")]
 pub async fn randomize_vga_buffer() {
-    WRITER.lock().randomize_vga_buffer().await;
+    let _screen = SCREEN_ANIMATION.lock().await;
+    let mut rng = SmallRng::seed_from_u64(0);
+
+    for row in 0..BUFFER_HEIGHT {
+        WRITER.lock().random_line(row, &mut rng);
+        crate::task::sleep::Sleep::new(Duration::from_nanos(1)).await
+    }
 }
 
 pub fn chars() {
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        for c in 0x00..0xff_u8 {
-            writer.write_byte(c);
-        }
-    });
+    let mut writer = WRITER.lock();
+    for c in 0x00..0xff_u8 {
+        writer.write_byte(c);
+    }
 }
 
 pub fn draw_window_frame(origin_x: usize, origin_y: usize, width: usize, height: usize) {
-    interrupts::without_interrupts(|| {
-        WRITER.lock().draw_window_frame(origin_x, origin_y, width, height);
-    });
+    WRITER.lock().draw_window_frame(origin_x, origin_y, width, height);
 }
 
 #[test_case]
@@ -497,7 +737,37 @@ fn test_println_output() {
     let s = "Some test string that fits on a single line";
     println!("{}", s);
     for (i, c) in s.chars().enumerate() {
-        let screen_char = WRITER.lock().buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        let screen_char = WRITER.lock().read_cell(i, BUFFER_HEIGHT - 2);
         assert_eq!(char::from(screen_char.cp437_character), c);
     }
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_selection_is_inverted_and_copied() {
+    println!("selected text");
+    let mut writer = WRITER.lock();
+    let row = BUFFER_HEIGHT - 2;
+    let plain = writer.read_cell(0, row);
+    writer.select((7, row), (0, row));
+    assert_eq!(writer.selected_text(), "selected");
+    assert_eq!(writer.buffer.chars[row][0].read().color_code, plain.color_code.inverted());
+    assert_eq!(writer.read_cell(0, row), plain);
+    writer.clear_selection();
+    assert_eq!(writer.buffer.chars[row][0].read(), plain);
+}
+
+#[test_case]
+fn test_scrollback_returns_to_live() {
+    println!("scrolled off");
+    for _ in 0..BUFFER_HEIGHT - 1 {
+        println!();
+    }
+    let mut writer = WRITER.lock();
+    let live = writer.read_cell(0, 0);
+    writer.scroll_view(1);
+    assert_eq!(char::from(writer.read_cell(0, 0).cp437_character), 's');
+    // new output shows the live screen again
+    writer.write_byte(b'x');
+    assert_eq!(writer.view_offset, 0);
+    assert_eq!(writer.read_cell(0, 0), live);
+}
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use blog_os::{error::Error, initrd, process::{self, ExitStatus}, thread};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_mapper(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn run(program: &str, args: &[&str]) -> ExitStatus {
+    let executable = initrd::find(program).expect("program not in initrd");
+    process::spawn_elf(program, executable, args, &[]).expect("spawning process failed").wait()
+}
+
+#[test_case]
+fn initrd_lists_programs() {
+    for program in ["args", "bss", "hello"] {
+        assert!(initrd::files().any(|file| file.name == program), "{} missing", program);
+    }
+    assert!(initrd::find("missing").is_none());
+}
+
+#[test_case]
+fn hello_exits() {
+    assert_eq!(run("hello", &["hello"]), ExitStatus::Exited(0));
+}
+
+#[test_case]
+fn arguments_are_passed_on_the_stack() {
+    assert_eq!(run("args", &["args", "first", "second"]), ExitStatus::Exited(3));
+    assert_eq!(run("args", &[]), ExitStatus::Exited(0));
+}
+
+#[test_case]
+fn data_is_writable_and_bss_zeroed() {
+    assert_eq!(run("bss", &["bss"]), ExitStatus::Exited(42));
+}
+
+#[test_case]
+fn invalid_executables_are_rejected() {
+    let result = process::spawn_elf("flat", &[0x0F, 0x0B], &[], &[]);
+    assert!(matches!(result, Err(Error::InvalidElf(_))));
+
+    let hello = initrd::find("hello").unwrap();
+    let result = process::spawn_elf("truncated", &hello[..100], &[], &[]);
+    assert!(matches!(result, Err(Error::InvalidElf(_))));
+}
@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll}
+};
+use blog_os::{
+    serial_print, serial_println, exit_qemu, QemuExitCode,
+    task::{executor::Executor, spawner}
+};
+
+/// Tasks alive at the same time, well above the old 100 slot queues.
+const TASKS_PER_ROUND: usize = 500;
+const ROUNDS: usize = 10;
+/// How many times each task yields, waking itself repeatedly every time.
+const YIELDS: usize = 3;
+const WAKES_PER_YIELD: usize = 4;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("executor_stress... ");
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    let mut executor = Executor::new();
+    spawner::spawn(async {
+        let completed = Arc::new(AtomicUsize::new(0));
+        for round in 0..ROUNDS {
+            let handles: Vec<_> = (0..TASKS_PER_ROUND)
+                .map(|i| {
+                    let completed = completed.clone();
+                    spawner::spawn(async move {
+                        for _ in 0..YIELDS {
+                            WakeRepeatedly::new(WAKES_PER_YIELD).await;
+                        }
+                        completed.fetch_add(1, Ordering::Relaxed);
+                        i
+                    })
+                })
+                .collect();
+
+            for (i, handle) in handles.into_iter().enumerate() {
+                assert_eq!(handle.await, Ok(i));
+            }
+            assert_eq!(completed.load(Ordering::Relaxed), (round + 1) * TASKS_PER_ROUND);
+        }
+
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    });
+    executor.run();
+}
+
+/// Returns pending once, after waking its task `wakes` times.
+struct WakeRepeatedly {
+    wakes: usize,
+    polled: bool,
+}
+
+impl WakeRepeatedly {
+    fn new(wakes: usize) -> Self {
+        WakeRepeatedly { wakes, polled: false }
+    }
+}
+
+impl Future for WakeRepeatedly {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.polled {
+            return Poll::Ready(());
+        }
+        self.polled = true;
+        for _ in 0..self.wakes {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
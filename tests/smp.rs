@@ -0,0 +1,132 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, time::Duration};
+use blog_os::{smp, thread, time::Instant};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_mapper(mapper, frame_allocator);
+    #[cfg(feature="acpi-feat")]
+    blog_os::acpi::init_acpi_info(boot_info.physical_memory_offset, false)
+        .expect("ACPI initialization failed");
+    blog_os::init();
+    thread::init();
+    smp::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Tests run with `-smp 4`.
+#[cfg(feature="acpi-feat")]
+const EXPECTED_CPUS: usize = 4;
+#[cfg(not(feature="acpi-feat"))]
+const EXPECTED_CPUS: usize = 1;
+
+#[test_case]
+fn all_cpus_online() {
+    assert_eq!(smp::online_cpus(), EXPECTED_CPUS);
+    let cpus = smp::cpu_snapshot();
+    assert_eq!(cpus.len(), EXPECTED_CPUS);
+    assert!(cpus.iter().all(|cpu| cpu.online));
+}
+
+#[test_case]
+fn apic_ids_are_unique() {
+    let mut apic_ids: Vec<u32> = smp::cpu_snapshot().iter().map(|cpu| cpu.apic_id).collect();
+    apic_ids.sort_unstable();
+    apic_ids.dedup();
+    assert_eq!(apic_ids.len(), EXPECTED_CPUS);
+}
+
+#[test_case]
+fn bootstrap_processor_keeps_running() {
+    // the other CPUs shouldn't disturb interrupts or threads here
+    let handle = thread::spawn(|| 42);
+    assert_eq!(handle.join(), 42);
+}
+
+/// Spins until `condition` holds, failing after a few seconds.
+fn wait_until(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out");
+        core::hint::spin_loop();
+    }
+}
+
+// the bootstrap processor runs the tests instead of an executor, so tasks
+// only run with application processors
+#[cfg(feature="acpi-feat")]
+#[test_case]
+fn tasks_spread_over_cpus() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use blog_os::{smp::percpu, task::spawner};
+
+    static CPUS_USED: AtomicU64 = AtomicU64::new(0);
+
+    let handles: Vec<_> = (0..4 * EXPECTED_CPUS)
+        .map(|_| spawner::spawn(async {
+            CPUS_USED.fetch_or(1 << percpu::current().index(), Ordering::Relaxed);
+            // keep the CPU busy, so the idle ones steal the other tasks
+            let busy_until = Instant::now() + Duration::from_millis(20);
+            while Instant::now() < busy_until {
+                core::hint::spin_loop();
+            }
+        }))
+        .collect();
+    wait_until(|| handles.iter().all(|handle| handle.is_finished()));
+
+    let application_processors = (1 << EXPECTED_CPUS) - 2;
+    assert_eq!(CPUS_USED.load(Ordering::Relaxed), application_processors);
+}
+
+#[cfg(feature="acpi-feat")]
+#[test_case]
+fn wake_up_halted_cpu() {
+    use blog_os::task::{spawner, sync::Notify};
+
+    static NOTIFY: Notify = Notify::new();
+
+    let handle = spawner::spawn(NOTIFY.notified());
+    // by now the task's CPU halts, waiting for the notification
+    thread::sleep(Duration::from_millis(50));
+    assert!(!handle.is_finished());
+    NOTIFY.notify_one();
+    wait_until(|| handle.is_finished());
+}
+
+#[cfg(feature="acpi-feat")]
+#[test_case]
+fn sleep_on_application_processor_finishes() {
+    use blog_os::task::{sleep::Sleep, spawner};
+
+    // its CPU halts with no timer of its own until the deadline
+    let handle = spawner::spawn(Sleep::new(Duration::from_millis(10)));
+    wait_until(|| handle.is_finished());
+}
@@ -0,0 +1,177 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, time::Duration};
+use blog_os::{
+    process::{self, ExitStatus, Fault},
+    syscall::SyscallError,
+    thread,
+    time::Instant
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_mapper(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn run(code: &[u8]) -> ExitStatus {
+    process::spawn_flat("test", code).expect("spawning process failed").wait()
+}
+
+/// Exit status of a process exiting with the result of a failed syscall.
+fn exited_with(err: SyscallError) -> ExitStatus {
+    let status = ExitStatus::Exited((err as u64).wrapping_neg() as u32);
+    assert_eq!(SyscallError::from_result((err as u64).wrapping_neg()), Some(err));
+    status
+}
+
+#[test_case]
+fn exit_with_syscall() {
+    // mov edi, 42; xor eax, eax; syscall
+    let code = [0xBF, 0x2A, 0x00, 0x00, 0x00, 0x31, 0xC0, 0x0F, 0x05];
+    assert_eq!(run(&code), ExitStatus::Exited(42));
+}
+
+#[test_case]
+fn exit_with_interrupt() {
+    // mov edi, 7; xor eax, eax; int 0x80
+    let code = [0xBF, 0x07, 0x00, 0x00, 0x00, 0x31, 0xC0, 0xCD, 0x80];
+    assert_eq!(run(&code), ExitStatus::Exited(7));
+}
+
+#[test_case]
+fn unknown_syscall_fails() {
+    // mov eax, 99; syscall; mov rdi, rax; xor eax, eax; syscall
+    let code = [
+        0xB8, 0x63, 0x00, 0x00, 0x00, 0x0F, 0x05,
+        0x48, 0x89, 0xC7, 0x31, 0xC0, 0x0F, 0x05,
+    ];
+    assert_eq!(run(&code), exited_with(SyscallError::NoSuchSyscall));
+}
+
+#[test_case]
+fn arguments_are_preserved() {
+    // mov edi, 5; mov eax, 4 (uptime); syscall; xor eax, eax; syscall
+    let code = [
+        0xBF, 0x05, 0x00, 0x00, 0x00, 0xB8, 0x04, 0x00, 0x00, 0x00, 0x0F, 0x05,
+        0x31, 0xC0, 0x0F, 0x05,
+    ];
+    assert_eq!(run(&code), ExitStatus::Exited(5));
+}
+
+#[test_case]
+fn write_returns_length() {
+    // mov rdi, USER_CODE_START; mov esi, 4; mov eax, 1 (write); syscall;
+    // mov rdi, rax; xor eax, eax; syscall
+    let code = [
+        0x48, 0xBF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+        0xBE, 0x04, 0x00, 0x00, 0x00, 0xB8, 0x01, 0x00, 0x00, 0x00, 0x0F, 0x05,
+        0x48, 0x89, 0xC7, 0x31, 0xC0, 0x0F, 0x05,
+    ];
+    assert_eq!(run(&code), ExitStatus::Exited(4));
+}
+
+#[test_case]
+fn write_from_kernel_memory_fails() {
+    // mov rdi, HEAP_START; mov esi, 1; mov eax, 1 (write); syscall;
+    // mov rdi, rax; xor eax, eax; syscall
+    let code = [
+        0x48, 0xBF, 0x00, 0x00, 0x44, 0x44, 0x44, 0x44, 0x00, 0x00,
+        0xBE, 0x01, 0x00, 0x00, 0x00, 0xB8, 0x01, 0x00, 0x00, 0x00, 0x0F, 0x05,
+        0x48, 0x89, 0xC7, 0x31, 0xC0, 0x0F, 0x05,
+    ];
+    assert_eq!(run(&code), exited_with(SyscallError::BadAddress));
+}
+
+#[test_case]
+fn read_into_code_fails() {
+    // mov rdi, USER_CODE_START; mov esi, 1; mov eax, 2 (read); syscall;
+    // mov rdi, rax; xor eax, eax; syscall
+    let code = [
+        0x48, 0xBF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+        0xBE, 0x01, 0x00, 0x00, 0x00, 0xB8, 0x02, 0x00, 0x00, 0x00, 0x0F, 0x05,
+        0x48, 0x89, 0xC7, 0x31, 0xC0, 0x0F, 0x05,
+    ];
+    assert_eq!(run(&code), exited_with(SyscallError::BadAddress));
+}
+
+#[test_case]
+fn sleep_blocks_process() {
+    // mov edi, 50; mov eax, 3 (sleep); syscall; xor edi, edi; xor eax, eax;
+    // syscall
+    let code = [
+        0xBF, 0x32, 0x00, 0x00, 0x00, 0xB8, 0x03, 0x00, 0x00, 0x00, 0x0F, 0x05,
+        0x31, 0xFF, 0x31, 0xC0, 0x0F, 0x05,
+    ];
+    let start = Instant::now();
+    assert_eq!(run(&code), ExitStatus::Exited(0));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
+
+#[test_case]
+fn interrupt_ends_blocked_read() {
+    // lea rdi, [rsp - 16]; mov esi, 1; mov eax, 2 (read); syscall;
+    // mov rdi, rax; xor eax, eax; syscall
+    let code = [
+        0x48, 0x8D, 0x7C, 0x24, 0xF0, 0xBE, 0x01, 0x00, 0x00, 0x00,
+        0xB8, 0x02, 0x00, 0x00, 0x00, 0x0F, 0x05,
+        0x48, 0x89, 0xC7, 0x31, 0xC0, 0x0F, 0x05,
+    ];
+    let process = process::spawn_flat("test", &code).expect("spawning process failed");
+    thread::sleep(Duration::from_millis(20));
+    assert!(!process.is_finished());
+    process.interrupt();
+    assert_eq!(process.wait(), ExitStatus::Interrupted);
+}
+
+#[test_case]
+fn mapped_memory_is_writable() {
+    // mov edi, 4096; mov esi, 3 (read | write); mov eax, 5 (mmap); syscall;
+    // mov byte [rax], 1; movzx edi, byte [rax]; xor eax, eax; syscall
+    let code = [
+        0xBF, 0x00, 0x10, 0x00, 0x00, 0xBE, 0x03, 0x00, 0x00, 0x00,
+        0xB8, 0x05, 0x00, 0x00, 0x00, 0x0F, 0x05,
+        0xC6, 0x00, 0x01, 0x0F, 0xB6, 0x38, 0x31, 0xC0, 0x0F, 0x05,
+    ];
+    assert_eq!(run(&code), ExitStatus::Exited(1));
+}
+
+#[test_case]
+fn read_only_mapping_is_not_writable() {
+    // mov edi, 4096; mov esi, 1 (read); mov eax, 5 (mmap); syscall;
+    // mov byte [rax], 1
+    let code = [
+        0xBF, 0x00, 0x10, 0x00, 0x00, 0xBE, 0x01, 0x00, 0x00, 0x00,
+        0xB8, 0x05, 0x00, 0x00, 0x00, 0x0F, 0x05,
+        0xC6, 0x00, 0x01,
+    ];
+    assert_eq!(run(&code), ExitStatus::Killed(Fault::PageFault));
+}
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll}
+};
+use blog_os::{
+    serial_print, serial_println, exit_qemu, QemuExitCode,
+    task::{executor::{self, Executor}, join::JoinError, spawner, Priority}
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("task_abort... ");
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    let mut executor = Executor::new();
+    spawner::spawn(async {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let dropped = Arc::new(AtomicBool::new(false));
+        let handle = spawner::spawn_named("aborted", Priority::default(), Spin {
+            polls: polls.clone(),
+            dropped: dropped.clone(),
+        });
+        while polls.load(Ordering::Relaxed) == 0 {
+            YieldNow(false).await;
+        }
+        assert!(is_registered("aborted"));
+
+        handle.abort();
+        assert_eq!(handle.await, Err(JoinError::Aborted));
+        assert!(dropped.load(Ordering::Relaxed));
+
+        // the task kept waking itself, it must not be queued nor polled again
+        let polls_at_abort = polls.load(Ordering::Relaxed);
+        for _ in 0..10 {
+            YieldNow(false).await;
+        }
+        assert_eq!(polls.load(Ordering::Relaxed), polls_at_abort);
+        assert!(!is_registered("aborted"));
+
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    });
+    executor.run();
+}
+
+fn is_registered(name: &str) -> bool {
+    executor::task_snapshot().iter().any(|task| task.name == name)
+}
+
+/// Never completes, waking its task on every poll so it's always queued.
+struct Spin {
+    polls: Arc<AtomicUsize>,
+    dropped: Arc<AtomicBool>,
+}
+
+impl Future for Spin {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+impl Drop for Spin {
+    fn drop(&mut self) {
+        self.dropped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Returns pending once, letting the other tasks run.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
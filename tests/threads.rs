@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration
+};
+use blog_os::{thread, time::Instant};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_mapper(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_returns_result() {
+    let handles: Vec<_> = (0..10u64)
+        .map(|i| thread::spawn(move || (0..=i).sum::<u64>()))
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        let i = i as u64;
+        assert_eq!(handle.join(), i * (i + 1) / 2);
+    }
+}
+
+#[test_case]
+fn yielding_threads_interleave() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    thread::yield_now();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(counter.load(Ordering::Relaxed), 400);
+}
+
+#[test_case]
+fn busy_thread_is_preempted() {
+    let flag = Arc::new(AtomicBool::new(false));
+    let thread_flag = flag.clone();
+    let handle = thread::spawn(move || thread_flag.store(true, Ordering::Relaxed));
+
+    // never yields, only the timer interrupt lets the other thread run
+    while !flag.load(Ordering::Relaxed) {
+        core::hint::spin_loop();
+    }
+    handle.join();
+}
+
+#[test_case]
+fn sleep_waits_for_duration() {
+    let duration = Duration::from_millis(100);
+    let handle = thread::spawn(move || {
+        let start = Instant::now();
+        thread::sleep(duration);
+        start.elapsed()
+    });
+    assert!(handle.join() >= duration);
+}
+
+#[test_case]
+fn stacks_are_reused() {
+    // far more threads than the stack region could map if stacks leaked
+    for _ in 0..50 {
+        let handles: Vec<_> = (0..100).map(|i| thread::spawn(move || i)).collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join(), i);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use blog_os::{gdt, process::{self, ExitStatus, Fault}, thread};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_mapper(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn run(code: &[u8]) -> ExitStatus {
+    process::spawn_flat("test", code).expect("spawning process failed").wait()
+}
+
+#[test_case]
+fn privileged_instruction_kills_process() {
+    // hlt
+    assert_eq!(run(&[0xF4]), ExitStatus::Killed(Fault::GeneralProtectionFault));
+}
+
+#[test_case]
+fn invalid_opcode_kills_process() {
+    // ud2
+    assert_eq!(run(&[0x0F, 0x0B]), ExitStatus::Killed(Fault::InvalidOpcode));
+}
+
+#[test_case]
+fn division_by_zero_kills_process() {
+    // xor ecx, ecx; div ecx
+    assert_eq!(run(&[0x31, 0xC9, 0xF7, 0xF1]), ExitStatus::Killed(Fault::DivideError));
+}
+
+#[test_case]
+fn kernel_memory_is_not_accessible() {
+    // mov rax, HEAP_START; mov byte [rax], 1
+    let code = [
+        0x48, 0xB8, 0x00, 0x00, 0x44, 0x44, 0x44, 0x44, 0x00, 0x00,
+        0xC6, 0x00, 0x01,
+    ];
+    assert_eq!(run(&code), ExitStatus::Killed(Fault::PageFault));
+}
+
+#[test_case]
+fn code_is_not_writable() {
+    // lea rax, [rip]; mov byte [rax], 1
+    let code = [0x48, 0x8D, 0x05, 0x00, 0x00, 0x00, 0x00, 0xC6, 0x00, 0x01];
+    assert_eq!(run(&code), ExitStatus::Killed(Fault::PageFault));
+}
+
+#[test_case]
+fn stack_is_usable() {
+    // push rax; pop rax; ud2
+    assert_eq!(run(&[0x50, 0x58, 0x0F, 0x0B]), ExitStatus::Killed(Fault::InvalidOpcode));
+}
+
+#[test_case]
+fn looping_process_is_preempted() {
+    // jmp $
+    let process = process::spawn_flat("loop", &[0xEB, 0xFE]).expect("spawning process failed");
+    // only returns if the timer interrupt switches away from user mode
+    thread::sleep(core::time::Duration::from_millis(100));
+    assert!(!process.is_finished());
+}
+
+#[test_case]
+fn interrupted_process_exits() {
+    // jmp $
+    let process = process::spawn_flat("loop", &[0xEB, 0xFE]).expect("spawning process failed");
+    thread::sleep(core::time::Duration::from_millis(20));
+    process.interrupt();
+    assert_eq!(process.wait(), ExitStatus::Interrupted);
+}
+
+#[test_case]
+fn loading_gs_leaves_kernel_working() {
+    // mov ax, user data selector; mov gs, ax, which sets the GS base to 0
+    let [low, high] = gdt::user_data_selector().0.to_le_bytes();
+    let mut code = alloc::vec![0x66, 0xB8, low, high, 0x8E, 0xE8];
+    code.extend_from_slice(&[
+        // sleep(10), the syscall and the timer interrupts run with that GS
+        0xB8, 0x03, 0x00, 0x00, 0x00,
+        0xBF, 0x0A, 0x00, 0x00, 0x00,
+        0x0F, 0x05,
+        // mov ecx, 0x1000000; loop $, to be preempted in user mode
+        0xB9, 0x00, 0x00, 0x00, 0x01,
+        0xE2, 0xFE,
+        // exit(7)
+        0x31, 0xC0,
+        0xBF, 0x07, 0x00, 0x00, 0x00,
+        0x0F, 0x05,
+    ]);
+    assert_eq!(run(&code), ExitStatus::Exited(7));
+}
@@ -0,0 +1,34 @@
+# Prints its arguments one per line and exits with their count.
+    .global _start
+    .text
+_start:
+    mov (%rsp), %r12            # argc
+    lea 8(%rsp), %r13           # argv
+    xor %r14d, %r14d
+next_arg:
+    cmp %r12, %r14
+    je done
+    mov (%r13,%r14,8), %rdi
+    xor %esi, %esi
+strlen:
+    cmpb $0, (%rdi,%rsi)
+    je print
+    inc %rsi
+    jmp strlen
+print:
+    mov $1, %eax                # write
+    syscall
+    lea newline(%rip), %rdi
+    mov $1, %esi
+    mov $1, %eax                # write
+    syscall
+    inc %r14
+    jmp next_arg
+done:
+    mov %r12, %rdi
+    xor %eax, %eax              # exit
+    syscall
+
+    .section .rodata
+newline:
+    .ascii "\n"
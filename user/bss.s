@@ -0,0 +1,19 @@
+# Exits with 42 if .data is writable and .bss, past the end of the file
+# contents, is zeroed.
+    .global _start
+    .text
+_start:
+    incq value(%rip)
+    mov value(%rip), %rdi
+    add counter(%rip), %rdi
+    xor %eax, %eax              # exit
+    syscall
+
+    .data
+value:
+    .quad 41
+
+    .bss
+    .skip 8192
+counter:
+    .skip 8
@@ -0,0 +1,17 @@
+# Prints a greeting and exits.
+    .global _start
+    .text
+_start:
+    lea message(%rip), %rdi
+    mov $message_len, %esi
+    mov $1, %eax                # write
+    syscall
+
+    xor %edi, %edi
+    xor %eax, %eax              # exit
+    syscall
+
+    .section .rodata
+message:
+    .ascii "Hello from user space!\n"
+    .set message_len, . - message